@@ -4,7 +4,25 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use ureq::RequestBuilder;
 
-pub struct Api(ureq::Agent);
+const HOST: &str = "graphql.anilist.co";
+
+pub struct Api {
+    agent: ureq::Agent,
+    /// GraphQL endpoint
+    url: String,
+}
+
+/// Cheap connectivity check against the AniList endpoint.
+pub fn is_reachable() -> bool {
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    let Ok(addrs) = (HOST, 443).to_socket_addrs() else {
+        return false;
+    };
+    addrs
+        .into_iter()
+        .any(|addr| TcpStream::connect_timeout(&addr, std::time::Duration::from_secs(5)).is_ok())
+}
 
 struct Query(Box<[u8]>);
 
@@ -77,7 +95,7 @@ struct Data<T> {
     data: T,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum MediaListStatus {
     Current,
@@ -88,15 +106,306 @@ pub enum MediaListStatus {
     Repeating,
 }
 
+impl std::fmt::Display for MediaListStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MediaListStatus::Current => "current",
+            MediaListStatus::Planning => "planning",
+            MediaListStatus::Completed => "completed",
+            MediaListStatus::Dropped => "dropped",
+            MediaListStatus::Paused => "paused",
+            MediaListStatus::Repeating => "repeating",
+        })
+    }
+}
+
+/// A date whose parts may be unknown, as used by AniList.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FuzzyDate {
+    pub year: Option<u32>,
+    pub month: Option<u32>,
+    pub day: Option<u32>,
+}
+
+impl FuzzyDate {
+    pub fn is_empty(&self) -> bool {
+        self.year.is_none() && self.month.is_none() && self.day.is_none()
+    }
+}
+
+/// List fields other than progress to change on the next sync.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ListChange {
+    pub status: Option<MediaListStatus>,
+    /// on a 0-10 scale
+    pub score: Option<f64>,
+    pub started_at: Option<FuzzyDate>,
+    pub completed_at: Option<FuzzyDate>,
+    /// times rewatched
+    pub repeat: Option<u64>,
+    pub notes: Option<String>,
+    /// names of the custom lists the entry is in, replacing the current ones
+    pub custom_lists: Option<Vec<String>>,
+    pub private: Option<bool>,
+    pub hidden: Option<bool>,
+}
+
+impl ListChange {
+    /// Fields set in `other` replace the ones in `self`.
+    pub fn merge(self, other: ListChange) -> ListChange {
+        ListChange {
+            status: other.status.or(self.status),
+            score: other.score.or(self.score),
+            started_at: other.started_at.or(self.started_at),
+            completed_at: other.completed_at.or(self.completed_at),
+            repeat: other.repeat.or(self.repeat),
+            notes: other.notes.or(self.notes),
+            custom_lists: other.custom_lists.or(self.custom_lists),
+            private: other.private.or(self.private),
+            hidden: other.hidden.or(self.hidden),
+        }
+    }
+}
+
+/// How the user rates anime on AniList, from `mediaListOptions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScoreFormat {
+    #[serde(rename = "POINT_100")]
+    Point100,
+    #[serde(rename = "POINT_10_DECIMAL")]
+    Point10Decimal,
+    #[serde(rename = "POINT_10")]
+    Point10,
+    #[serde(rename = "POINT_5")]
+    Point5,
+    #[serde(rename = "POINT_3")]
+    Point3,
+}
+
+impl ScoreFormat {
+    /// Converts a score in this format to the 0-10 scale of [`ListChange`],
+    /// `None` if out of range or not a whole number when it must be.
+    pub fn normalize(self, score: f64) -> Option<f64> {
+        let (max, whole) = match self {
+            ScoreFormat::Point100 => (100.0, true),
+            ScoreFormat::Point10Decimal => (10.0, false),
+            ScoreFormat::Point10 => (10.0, true),
+            ScoreFormat::Point5 => (5.0, true),
+            ScoreFormat::Point3 => (3.0, true),
+        };
+        if !(0.0..=max).contains(&score) || (whole && score.fract() != 0.0) {
+            return None;
+        }
+        Some(match self {
+            // AniList stores the smileys as 35, 60 and 85 out of 100
+            ScoreFormat::Point3 if score > 0.0 => [3.5, 6.0, 8.5][score as usize - 1],
+            _ => score * 10.0 / max,
+        })
+    }
+
+    /// Converts a score on the 0-10 scale of [`ListChange`] back to this
+    /// format, the inverse of [`ScoreFormat::normalize`].
+    pub fn denormalize(self, score: f64) -> f64 {
+        match self {
+            ScoreFormat::Point100 => (score * 10.0).round(),
+            ScoreFormat::Point10Decimal => score,
+            ScoreFormat::Point10 => score.round(),
+            ScoreFormat::Point5 => (score / 2.0).round(),
+            ScoreFormat::Point3 => match score {
+                0.0 => 0.0,
+                ..=3.5 => 1.0,
+                ..=6.0 => 2.0,
+                _ => 3.0,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ScoreFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ScoreFormat::Point100 => "0-100",
+            ScoreFormat::Point10Decimal => "0-10 with decimals",
+            ScoreFormat::Point10 => "0-10",
+            ScoreFormat::Point5 => "0-5",
+            ScoreFormat::Point3 => "0-3",
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Title {
+    #[serde(rename(serialize = "user_preferred"))]
+    pub userPreferred: Option<String>,
+    pub english: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Anime {
+    pub title: Option<String>,
     pub episodes: Option<u64>,
     pub progress: u64,
+    /// unix timestamp in seconds of the last change to the list entry
+    pub updated_at: Option<u64>,
+    /// `None` if not in the list
+    pub status: Option<MediaListStatus>,
+    /// times rewatched
+    pub repeat: u64,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ListProgress {
+    progress: u64,
+    updatedAt: Option<u64>,
+    status: Option<MediaListStatus>,
+    repeat: Option<u64>,
+}
+
+/// The next episode of an anime still airing.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Airing {
+    pub episode: u64,
+    /// unix timestamp in seconds
+    pub airing_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MediaStatus {
+    Finished,
+    Releasing,
+    NotYetReleased,
+    Cancelled,
+    Hiatus,
+}
+
+impl std::fmt::Display for MediaStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MediaStatus::Finished => "finished",
+            MediaStatus::Releasing => "releasing",
+            MediaStatus::NotYetReleased => "not yet released",
+            MediaStatus::Cancelled => "cancelled",
+            MediaStatus::Hiatus => "hiatus",
+        })
+    }
+}
+
+/// Another media related to an anime, like a sequel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Relation {
+    pub id: u64,
+    /// AniList relation type, like `SEQUEL`
+    pub relation: String,
+}
+
+/// Details of an anime, cached locally.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaInfo {
+    pub title: Option<String>,
+    pub episodes: Option<u64>,
+    pub next_airing: Option<Airing>,
+    pub status: Option<MediaStatus>,
+    /// like `TV` or `MOVIE`
+    pub format: Option<String>,
+    /// minutes per episode
+    pub duration: Option<u64>,
+    pub id_mal: Option<u64>,
+    /// URL of the cover image
+    pub cover: Option<String>,
+    pub relations: Vec<Relation>,
+}
+
+#[derive(Debug)]
+pub struct Manga {
+    pub chapters: Option<u64>,
+    pub volumes: Option<u64>,
+    pub progress: u64,
+    pub progress_volumes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum MediaSeason {
+    Winter,
+    Spring,
+    Summer,
+    Fall,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MediaFormat {
+    Tv,
+    TvShort,
+    Movie,
+    Special,
+    Ova,
+    Ona,
+    Music,
+}
+
+/// Restricts [`Api::search`] results.
+#[derive(Debug, Default)]
+pub struct SearchFilter {
+    pub year: Option<u32>,
+    pub season: Option<MediaSeason>,
+    pub format: Option<MediaFormat>,
+}
+
+/// An anime found by [`Api::search`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct Media {
+    pub id: u64,
+    pub title: Title,
+    pub episodes: Option<u64>,
+    pub status: Option<MediaStatus>,
+    pub format: Option<String>,
+    pub season: Option<MediaSeason>,
+    pub season_year: Option<u32>,
+}
+
+/// An entry of the viewer's list.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct MediaList {
+    pub media_id: u64,
+    pub status: Option<MediaListStatus>,
+    /// on a 0-10 scale
+    pub score: Option<f64>,
+    pub progress: Option<u64>,
+    /// times rewatched
+    pub repeat: Option<u64>,
+    pub started_at: Option<FuzzyDate>,
+    pub completed_at: Option<FuzzyDate>,
+    /// unix timestamp in seconds
+    pub updated_at: Option<u64>,
+    pub notes: Option<String>,
+    pub media: Option<ListMedia>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct ListMedia {
+    pub id_mal: Option<u64>,
+    pub episodes: Option<u64>,
+    pub title: Title,
 }
 
 impl Api {
     pub fn new() -> Self {
-        Self(ureq::Agent::new_with_defaults())
+        Self::with_url(&format!("https://{HOST}"))
+    }
+
+    /// Talks to the GraphQL endpoint at `url`.
+    pub fn with_url(url: &str) -> Self {
+        Self {
+            agent: ureq::Agent::new_with_defaults(),
+            url: url.to_string(),
+        }
     }
 
     fn request<T: DeserializeOwned>(
@@ -112,8 +421,8 @@ impl Api {
         }
 
         Ok(put_auth(
-            self.0
-                .post("https://graphql.anilist.co")
+            self.agent
+                .post(&self.url)
                 .header("Accept", "application/json")
                 .header("Content-Type", "application/json"),
             token,
@@ -143,10 +452,74 @@ impl Api {
         .map(|v| v.Viewer.id)
     }
 
-    fn _get_anime(&self, id: u64) -> Result<Option<u64>, ureq::Error> {
+    pub fn score_format(&self, token: &str) -> Result<ScoreFormat, ureq::Error> {
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct MediaListOptions {
+            scoreFormat: ScoreFormat,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Viewer {
+            mediaListOptions: MediaListOptions,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Container {
+            Viewer: Viewer,
+        }
+
+        self.request::<Container>(
+            Some(token),
+            QueryBuilder::new("query { Viewer { mediaListOptions { scoreFormat } } }").build(),
+        )
+        .map(|v| v.Viewer.mediaListOptions.scoreFormat)
+    }
+
+    pub fn get_media(&self, id: u64) -> Result<MediaInfo, ureq::Error> {
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct NextAiringEpisode {
+            episode: u64,
+            airingAt: u64,
+        }
+
+        #[derive(Deserialize)]
+        struct CoverImage {
+            large: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        struct Node {
+            id: u64,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Edge {
+            relationType: String,
+            node: Node,
+        }
+
         #[derive(Deserialize)]
+        struct Relations {
+            edges: Vec<Edge>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
         struct Media {
+            title: Title,
             episodes: Option<u64>,
+            nextAiringEpisode: Option<NextAiringEpisode>,
+            status: Option<MediaStatus>,
+            format: Option<String>,
+            duration: Option<u64>,
+            idMal: Option<u64>,
+            coverImage: Option<CoverImage>,
+            relations: Option<Relations>,
         }
 
         #[derive(Deserialize)]
@@ -158,31 +531,83 @@ impl Api {
         const QUERY: &str = "
         query ($id: Int) {
             Media(id: $id, type: ANIME) {
+                title {
+                    userPreferred
+                }
                 episodes
+                nextAiringEpisode {
+                    episode
+                    airingAt
+                }
+                status
+                format
+                duration
+                idMal
+                coverImage {
+                    large
+                }
+                relations {
+                    edges {
+                        relationType
+                        node {
+                            id
+                        }
+                    }
+                }
             }
         }
         ";
 
-        self.request::<Container>(None, QueryBuilder::new(QUERY).add("id", &id)?.build())
-            .map(|p| p.Media.episodes)
+        let media = self
+            .request::<Container>(None, QueryBuilder::new(QUERY).add("id", &id)?.build())?
+            .Media;
+        Ok(MediaInfo {
+            title: media.title.userPreferred,
+            episodes: media.episodes,
+            next_airing: media.nextAiringEpisode.map(|next| Airing {
+                episode: next.episode,
+                airing_at: next.airingAt,
+            }),
+            status: media.status,
+            format: media.format,
+            duration: media.duration,
+            id_mal: media.idMal,
+            cover: media.coverImage.and_then(|cover| cover.large),
+            relations: media
+                .relations
+                .map(|relations| {
+                    relations
+                        .edges
+                        .into_iter()
+                        .map(|edge| Relation {
+                            id: edge.node.id,
+                            relation: edge.relationType,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
     }
 
-    fn _get_progess(&self, token: &str, user_id: u64, id: u64) -> Result<u64, ureq::Error> {
-        #[derive(Deserialize)]
-        struct MediaList {
-            progress: u64,
-        }
-
+    fn _get_progess(
+        &self,
+        token: &str,
+        user_id: u64,
+        id: u64,
+    ) -> Result<ListProgress, ureq::Error> {
         #[derive(Deserialize)]
         #[allow(non_snake_case)]
         struct Container {
-            MediaList: MediaList,
+            MediaList: ListProgress,
         }
 
         const QUERY: &str = "
         query ($userId: Int, $mediaId: Int) {
             MediaList(userId: $userId, mediaId: $mediaId, type: ANIME) {
                 progress
+                updatedAt
+                status
+                repeat
             }
         }
         ";
@@ -194,29 +619,116 @@ impl Api {
                 .add("mediaId", &id)?
                 .build(),
         )
-        .map(|p| p.MediaList.progress)
+        .map(|p| p.MediaList)
     }
 
-    pub fn get_progress(&self, token: &str, user_id: u64, id: u64) -> Result<Anime, ureq::Error> {
-        let episodes = self._get_anime(id)?;
-        let progress = match self._get_progess(token, user_id, id) {
-            Ok(p) => p,
-            Err(ureq::Error::StatusCode(404)) => 0,
+    /// List entry of `id`, with the details in `media`.
+    pub fn get_progress(
+        &self,
+        token: &str,
+        user_id: u64,
+        id: u64,
+        media: MediaInfo,
+    ) -> Result<Anime, ureq::Error> {
+        let list = match self._get_progess(token, user_id, id) {
+            Ok(list) => Some(list),
+            Err(ureq::Error::StatusCode(404)) => None,
             Err(err) => return Err(err),
         };
-        Ok(Anime { episodes, progress })
+        Ok(Anime {
+            title: media.title,
+            episodes: media.episodes,
+            progress: list.as_ref().map_or(0, |list| list.progress),
+            updated_at: list.as_ref().and_then(|list| list.updatedAt),
+            status: list.as_ref().and_then(|list| list.status),
+            repeat: list.and_then(|list| list.repeat).unwrap_or(0),
+        })
     }
 
-    pub fn set_progress(
+    pub fn get_manga(&self, token: &str, user_id: u64, id: u64) -> Result<Manga, ureq::Error> {
+        #[derive(Deserialize)]
+        struct Media {
+            chapters: Option<u64>,
+            volumes: Option<u64>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct MediaList {
+            progress: u64,
+            progressVolumes: Option<u64>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct MediaContainer {
+            Media: Media,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct ListContainer {
+            MediaList: MediaList,
+        }
+
+        const MEDIA_QUERY: &str = "
+        query ($id: Int) {
+            Media(id: $id, type: MANGA) {
+                chapters
+                volumes
+            }
+        }
+        ";
+
+        const LIST_QUERY: &str = "
+        query ($userId: Int, $mediaId: Int) {
+            MediaList(userId: $userId, mediaId: $mediaId, type: MANGA) {
+                progress
+                progressVolumes
+            }
+        }
+        ";
+
+        let media = self
+            .request::<MediaContainer>(
+                None,
+                QueryBuilder::new(MEDIA_QUERY).add("id", &id)?.build(),
+            )?
+            .Media;
+        let list = match self.request::<ListContainer>(
+            Some(token),
+            QueryBuilder::new(LIST_QUERY)
+                .add("userId", &user_id)?
+                .add("mediaId", &id)?
+                .build(),
+        ) {
+            Ok(list) => Some(list.MediaList),
+            Err(ureq::Error::StatusCode(404)) => None,
+            Err(err) => return Err(err),
+        };
+        Ok(Manga {
+            chapters: media.chapters,
+            volumes: media.volumes,
+            progress: list.as_ref().map_or(0, |list| list.progress),
+            progress_volumes: list.and_then(|list| list.progressVolumes),
+        })
+    }
+
+    /// Saves the reading progress of a manga, completing it once the last
+    /// chapter or volume is reached.
+    pub fn set_manga_progress(
         &self,
         token: &str,
         id: u64,
-        progress: u64,
-        total: Option<u64>,
-    ) -> Result<u64, ureq::Error> {
+        chapter: u64,
+        volume: Option<u64>,
+        manga: &Manga,
+    ) -> Result<(u64, Option<u64>), ureq::Error> {
         #[derive(Deserialize)]
+        #[allow(non_snake_case)]
         struct SaveMediaListEntry {
             progress: u64,
+            progressVolumes: Option<u64>,
         }
 
         #[derive(Deserialize)]
@@ -226,31 +738,376 @@ impl Api {
         }
 
         const QUERY: &str = "
-        mutation ($mediaId: Int, $status: MediaListStatus, $progress: Int) {
-            SaveMediaListEntry (mediaId: $mediaId, status: $status, progress: $progress) {
+        mutation ($mediaId: Int, $status: MediaListStatus, $progress: Int, $progressVolumes: Int) {
+            SaveMediaListEntry (
+                mediaId: $mediaId,
+                status: $status,
+                progress: $progress,
+                progressVolumes: $progressVolumes
+            ) {
                 progress
+                progressVolumes
             }
         }
         ";
-        self.request::<Container>(
+
+        let completed = manga.chapters.is_some_and(|chapters| chapter >= chapters)
+            || manga
+                .volumes
+                .zip(volume)
+                .is_some_and(|(volumes, volume)| volume >= volumes);
+        let mut query = QueryBuilder::new(QUERY)
+            .add("mediaId", &id)?
+            .add("progress", &chapter)?
+            .add(
+                "status",
+                &if completed {
+                    MediaListStatus::Completed
+                } else {
+                    MediaListStatus::Current
+                },
+            )?;
+        if let Some(volume) = volume {
+            query.push("progressVolumes", &volume)?;
+        }
+        self.request::<Container>(Some(token), query.build())
+            .map(|p| {
+                (
+                    p.SaveMediaListEntry.progress,
+                    p.SaveMediaListEntry.progressVolumes,
+                )
+            })
+    }
+
+    /// Fetches the whole anime list of `user_id`.
+    pub fn get_list(&self, token: &str, user_id: u64) -> Result<Vec<MediaList>, ureq::Error> {
+        #[derive(Deserialize)]
+        struct Group {
+            entries: Vec<MediaList>,
+        }
+
+        #[derive(Deserialize)]
+        struct MediaListCollection {
+            lists: Vec<Group>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Container {
+            MediaListCollection: MediaListCollection,
+        }
+
+        const QUERY: &str = "
+        query ($userId: Int) {
+            MediaListCollection(userId: $userId, type: ANIME) {
+                lists {
+                    entries {
+                        mediaId
+                        status
+                        score(format: POINT_10_DECIMAL)
+                        progress
+                        repeat
+                        startedAt {
+                            year
+                            month
+                            day
+                        }
+                        completedAt {
+                            year
+                            month
+                            day
+                        }
+                        updatedAt
+                        media {
+                            idMal
+                            episodes
+                            title {
+                                userPreferred
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        ";
+
+        let mut entries = self
+            .request::<Container>(
+                Some(token),
+                QueryBuilder::new(QUERY).add("userId", &user_id)?.build(),
+            )?
+            .MediaListCollection
+            .lists
+            .into_iter()
+            .flat_map(|group| group.entries)
+            .collect::<Vec<_>>();
+        // custom lists repeat the entries
+        entries.sort_by_key(|entry| entry.media_id);
+        entries.dedup_by_key(|entry| entry.media_id);
+        Ok(entries)
+    }
+
+    pub fn search(&self, search: &str, filter: &SearchFilter) -> Result<Vec<Media>, ureq::Error> {
+        #[derive(Deserialize)]
+        struct Page {
+            media: Vec<Media>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Container {
+            Page: Page,
+        }
+
+        const QUERY: &str = "
+        query ($search: String, $seasonYear: Int, $season: MediaSeason, $format: MediaFormat) {
+            Page(perPage: 20) {
+                media(
+                    search: $search,
+                    type: ANIME,
+                    seasonYear: $seasonYear,
+                    season: $season,
+                    format: $format
+                ) {
+                    id
+                    title {
+                        userPreferred
+                        english
+                    }
+                    episodes
+                    status
+                    format
+                    season
+                    seasonYear
+                }
+            }
+        }
+        ";
+
+        let mut query = QueryBuilder::new(QUERY).add("search", &search)?;
+        if let Some(year) = filter.year {
+            query.push("seasonYear", &year)?;
+        }
+        if let Some(season) = filter.season {
+            query.push("season", &season)?;
+        }
+        if let Some(format) = filter.format {
+            query.push("format", &format)?;
+        }
+        self.request::<Container>(None, query.build())
+            .map(|p| p.Page.media)
+    }
+
+    /// The viewer's list entry of `id`, `None` if not in the list.
+    pub fn get_list_entry(
+        &self,
+        token: &str,
+        user_id: u64,
+        id: u64,
+    ) -> Result<Option<MediaList>, ureq::Error> {
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Container {
+            MediaList: MediaList,
+        }
+
+        const QUERY: &str = "
+        query ($userId: Int, $mediaId: Int) {
+            MediaList(userId: $userId, mediaId: $mediaId, type: ANIME) {
+                mediaId
+                status
+                score(format: POINT_10_DECIMAL)
+                progress
+                repeat
+                startedAt {
+                    year
+                    month
+                    day
+                }
+                completedAt {
+                    year
+                    month
+                    day
+                }
+                updatedAt
+                notes
+            }
+        }
+        ";
+
+        match self.request::<Container>(
             Some(token),
             QueryBuilder::new(QUERY)
+                .add("userId", &user_id)?
                 .add("mediaId", &id)?
-                .add("progress", &progress)?
-                .add(
-                    "status",
-                    &if total
-                        .as_ref()
-                        .map(|total| progress == *total)
-                        .unwrap_or(false)
-                    {
-                        MediaListStatus::Completed
-                    } else {
-                        MediaListStatus::Current
-                    },
-                )?
                 .build(),
-        )
-        .map(|p| p.SaveMediaListEntry.progress)
+        ) {
+            Ok(entry) => Ok(Some(entry.MediaList)),
+            Err(ureq::Error::StatusCode(404)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Maps MyAnimeList ids to AniList ones, with the AniList title. Ids
+    /// unknown to AniList are left out.
+    pub fn get_ids_by_mal(
+        &self,
+        mal_ids: &[u64],
+    ) -> Result<Vec<(u64, u64, Option<String>)>, ureq::Error> {
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Media {
+            id: u64,
+            idMal: Option<u64>,
+            title: Title,
+        }
+
+        #[derive(Deserialize)]
+        struct Page {
+            media: Vec<Media>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Container {
+            Page: Page,
+        }
+
+        const QUERY: &str = "
+        query ($ids: [Int]) {
+            Page(perPage: 50) {
+                media(idMal_in: $ids, type: ANIME) {
+                    id
+                    idMal
+                    title {
+                        userPreferred
+                    }
+                }
+            }
+        }
+        ";
+
+        let mut ids = Vec::new();
+        for chunk in mal_ids.chunks(50) {
+            let page = self
+                .request::<Container>(None, QueryBuilder::new(QUERY).add("ids", &chunk)?.build())?
+                .Page;
+            ids.extend(page.media.into_iter().filter_map(|media| {
+                media
+                    .idMal
+                    .map(|mal_id| (mal_id, media.id, media.title.userPreferred))
+            }));
+        }
+        Ok(ids)
+    }
+
+    /// Saves `progress`, along with the other fields in `change`. Without a
+    /// progress or a status in `change`, the current ones are kept.
+    pub fn set_progress(
+        &self,
+        token: &str,
+        id: u64,
+        progress: Option<u64>,
+        change: &ListChange,
+    ) -> Result<u64, ureq::Error> {
+        #[derive(Deserialize)]
+        struct SaveMediaListEntry {
+            progress: u64,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Container {
+            SaveMediaListEntry: SaveMediaListEntry,
+        }
+
+        const QUERY: &str = "
+        mutation (
+            $mediaId: Int,
+            $status: MediaListStatus,
+            $progress: Int,
+            $scoreRaw: Int,
+            $repeat: Int,
+            $startedAt: FuzzyDateInput,
+            $completedAt: FuzzyDateInput,
+            $notes: String,
+            $customLists: [String],
+            $private: Boolean,
+            $hidden: Boolean
+        ) {
+            SaveMediaListEntry (
+                mediaId: $mediaId,
+                status: $status,
+                progress: $progress,
+                scoreRaw: $scoreRaw,
+                repeat: $repeat,
+                startedAt: $startedAt,
+                completedAt: $completedAt,
+                notes: $notes,
+                customLists: $customLists,
+                private: $private,
+                hiddenFromStatusLists: $hidden
+            ) {
+                progress
+            }
+        }
+        ";
+        let mut query = QueryBuilder::new(QUERY).add("mediaId", &id)?;
+        if let Some(progress) = progress {
+            query.push("progress", &progress)?;
+        }
+        if let Some(status) = change.status {
+            query.push("status", &status)?;
+        }
+        if let Some(score) = change.score {
+            query.push("scoreRaw", &((score * 10.0).round() as u64))?;
+        }
+        if let Some(repeat) = change.repeat {
+            query.push("repeat", &repeat)?;
+        }
+        if let Some(started_at) = change.started_at {
+            query.push("startedAt", &started_at)?;
+        }
+        if let Some(completed_at) = change.completed_at {
+            query.push("completedAt", &completed_at)?;
+        }
+        if let Some(notes) = &change.notes {
+            query.push("notes", notes)?;
+        }
+        if let Some(custom_lists) = &change.custom_lists {
+            query.push("customLists", custom_lists)?;
+        }
+        if let Some(private) = change.private {
+            query.push("private", &private)?;
+        }
+        if let Some(hidden) = change.hidden {
+            query.push("hidden", &hidden)?;
+        }
+        self.request::<Container>(Some(token), query.build())
+            .map(|p| p.SaveMediaListEntry.progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScoreFormat;
+
+    #[test]
+    fn denormalize_inverts_normalize() {
+        for (format, scores) in [
+            (ScoreFormat::Point100, &[0.0, 1.0, 55.0, 100.0][..]),
+            (ScoreFormat::Point10Decimal, &[0.0, 0.5, 7.5, 10.0]),
+            (ScoreFormat::Point10, &[0.0, 1.0, 7.0, 10.0]),
+            (ScoreFormat::Point5, &[0.0, 1.0, 3.0, 5.0]),
+            (ScoreFormat::Point3, &[0.0, 1.0, 2.0, 3.0]),
+        ] {
+            for &score in scores {
+                let normalized = format.normalize(score).unwrap();
+                assert_eq!(format.denormalize(normalized), score, "{format:?} {score}");
+            }
+        }
+        // scores set in another format are rounded
+        assert_eq!(ScoreFormat::Point5.denormalize(7.5), 4.0);
+        assert_eq!(ScoreFormat::Point3.denormalize(5.0), 2.0);
     }
 }
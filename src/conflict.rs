@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+/// How to settle a pending local episode that disagrees with AniList.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// push the local episode, even if lower
+    LocalWins,
+    /// adopt the remote episode, dropping the local one
+    RemoteWins,
+    /// keep whichever is higher
+    #[default]
+    HighestWins,
+    /// ask on the terminal, leaving the conflict pending when not interactive
+    Ask,
+}
+
+impl std::fmt::Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Policy::LocalWins => "local-wins",
+            Policy::RemoteWins => "remote-wins",
+            Policy::HighestWins => "highest-wins",
+            Policy::Ask => "ask",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Local,
+    Remote,
+    /// left pending for a later sync
+    Deferred,
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Decision::Local => "local",
+            Decision::Remote => "remote",
+            Decision::Deferred => "deferred",
+        })
+    }
+}
+
+/// Whether the remote progress disagrees with a pending local episode in a
+/// way a plain push would not settle: the remote is ahead, or it changed
+/// after the local episode was queued.
+pub fn is_conflict(
+    local: u64,
+    remote: u64,
+    remote_updated_at: Option<u64>,
+    queued_at: Option<u64>,
+) -> bool {
+    if local == remote {
+        return false;
+    }
+    if remote > local {
+        return true;
+    }
+    matches!((remote_updated_at, queued_at), (Some(updated), Some(queued)) if updated > queued)
+}
+
+pub fn resolve(
+    policy: Policy,
+    local: u64,
+    remote: u64,
+    ask: impl FnOnce() -> Option<Decision>,
+) -> Decision {
+    match policy {
+        Policy::LocalWins => Decision::Local,
+        Policy::RemoteWins => Decision::Remote,
+        Policy::HighestWins if local > remote => Decision::Local,
+        Policy::HighestWins => Decision::Remote,
+        Policy::Ask => ask().unwrap_or(Decision::Deferred),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Decision::{Deferred, Local, Remote},
+        Policy, is_conflict, resolve,
+    };
+
+    const QUEUED_AT: u64 = 1_000;
+
+    /// `(local, remote, remote_updated_at)` of each case.
+    const CASES: [(&str, u64, u64, Option<u64>); 3] = [
+        ("local ahead", 5, 3, Some(QUEUED_AT - 1)),
+        ("remote ahead", 3, 5, Some(QUEUED_AT - 1)),
+        ("remote newer than queued", 5, 3, Some(QUEUED_AT + 1)),
+    ];
+
+    #[test]
+    fn conflicts() {
+        let expected = [false, true, true];
+        for ((name, local, remote, updated_at), expected) in CASES.into_iter().zip(expected) {
+            assert_eq!(
+                is_conflict(local, remote, updated_at, Some(QUEUED_AT)),
+                expected,
+                "{name}"
+            );
+        }
+        assert!(!is_conflict(5, 5, Some(QUEUED_AT + 1), Some(QUEUED_AT)));
+        assert!(!is_conflict(5, 3, None, Some(QUEUED_AT)));
+        assert!(!is_conflict(5, 3, Some(QUEUED_AT + 1), None));
+    }
+
+    #[test]
+    fn resolutions() {
+        let table = [
+            (Policy::LocalWins, [Local, Local, Local]),
+            (Policy::RemoteWins, [Remote, Remote, Remote]),
+            (Policy::HighestWins, [Local, Remote, Local]),
+            (Policy::Ask, [Deferred, Deferred, Deferred]),
+        ];
+        for (policy, expected) in table {
+            for ((name, local, remote, _), expected) in CASES.into_iter().zip(expected) {
+                assert_eq!(
+                    resolve(policy, local, remote, || None),
+                    expected,
+                    "{policy} with {name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ask_is_only_called_by_ask() {
+        for policy in [Policy::LocalWins, Policy::RemoteWins, Policy::HighestWins] {
+            resolve(policy, 5, 3, || panic!("{policy} asked"));
+        }
+        for answer in [Local, Remote, Deferred]
+            .map(Some)
+            .into_iter()
+            .chain([None])
+        {
+            let expected = answer.unwrap_or(Deferred);
+            assert_eq!(resolve(Policy::Ask, 5, 3, || answer), expected);
+        }
+    }
+}
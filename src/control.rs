@@ -0,0 +1,103 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::UnixStream,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{api::ListChange, paths};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Scrobble {
+        anilist_id: u64,
+        episode: u64,
+        #[serde(default)]
+        force: bool,
+        /// group of extra AniList accounts to scrobble to
+        #[serde(default)]
+        group: Option<String>,
+        /// list fields to change along with the episode
+        #[serde(default)]
+        change: Option<ListChange>,
+    },
+    Rate {
+        anilist_id: u64,
+        change: ListChange,
+    },
+    Read {
+        manga_id: u64,
+        chapter: u64,
+        #[serde(default)]
+        volume: Option<u64>,
+    },
+    Sync,
+    Status,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status(Status),
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub pending: usize,
+    #[serde(default)]
+    pub manga_pending: usize,
+    pub syncing: bool,
+    /// seconds since the last sync finished
+    pub last_sync: Option<u64>,
+    pub last_error: Option<String>,
+    /// seconds until the next scheduled retry
+    pub next_retry: Option<u64>,
+}
+
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    line: String,
+}
+
+impl Client {
+    /// Connects to the running daemon, if any.
+    pub fn connect() -> Result<Option<Self>> {
+        match UnixStream::connect(paths::socket_path()) {
+            Ok(stream) => Ok(Some(Self {
+                reader: BufReader::new(stream),
+                line: String::new(),
+            })),
+            Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+                Ok(None)
+            }
+            Err(err) => Err(err).context("cannot connect to daemon"),
+        }
+    }
+
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        let mut buf = serde_json::to_vec(request)?;
+        buf.push(b'\n');
+        self.reader
+            .get_mut()
+            .write_all(&buf)
+            .context("cannot send request to daemon")?;
+
+        self.line.clear();
+        if self
+            .reader
+            .read_line(&mut self.line)
+            .context("cannot read response from daemon")?
+            == 0
+        {
+            bail!("daemon closed the connection");
+        }
+        match serde_json::from_str(&self.line).context("invalid response from daemon")? {
+            Response::Error { message } => bail!(message),
+            response => Ok(response),
+        }
+    }
+}
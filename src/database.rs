@@ -1,33 +1,35 @@
-use std::{
-    mem::ManuallyDrop,
-    sync::{Arc, Mutex},
-};
+use std::path::Path;
 
 use anyhow::{Context, Result, bail};
-use heed::types::{Bytes, Str};
+use heed::types::{Bytes, SerdeBincode, Str, Unit};
 use serde::{Deserialize, Serialize};
 
-pub type U64 = heed::types::U64<heed::byteorder::LittleEndian>;
+use crate::api::ListChange;
 
-#[derive(Debug, Clone)]
-struct Delayed<T>(Arc<Mutex<Option<T>>>);
+mod changes;
+mod check;
+mod conflicts;
+pub mod dump;
+mod history;
+mod manga;
+mod media;
+mod migrations;
+mod pending;
+mod pull;
+mod rewatches;
+#[cfg(test)]
+pub mod testing;
+mod trackers;
 
-impl<T> Delayed<T> {
-    pub fn new() -> Self {
-        Self(Arc::new(Mutex::new(None)))
-    }
+pub use changes::QueuedChange;
+pub use conflicts::Conflict;
+pub use history::HistoryEntry;
+pub use manga::Reading;
+pub use trackers::Credentials;
 
-    pub fn get<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
-        let mut data = self.0.lock().unwrap();
-        if let Some(data) = data.as_ref() {
-            return Ok(unsafe { core::mem::transmute::<&T, &T>(data) });
-        }
-        *data = Some(f()?);
-        Ok(unsafe { core::mem::transmute::<&T, &T>(data.as_ref().unwrap_unchecked()) })
-    }
-}
+pub type U64 = heed::types::U64<heed::byteorder::LittleEndian>;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub token: String,
     pub id: u64,
@@ -40,18 +42,130 @@ impl AsRef<User> for User {
     }
 }
 
+/// Backoff state of an anime whose sync failed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Retry {
+    pub attempts: u32,
+    /// unix timestamp in seconds
+    pub next_attempt: u64,
+}
+
+/// First retry delay in seconds, doubled on every failed attempt.
+pub const RETRY_BASE: u64 = 60;
+/// Maximum retry delay in seconds.
+const RETRY_MAX: u64 = 6 * 60 * 60;
+
+impl Retry {
+    fn failed(prev: Option<Retry>) -> Self {
+        Self::failed_at(prev, now())
+    }
+
+    /// Backoff after a failure at `now`, following the `prev` ones.
+    fn failed_at(prev: Option<Retry>, now: u64) -> Self {
+        let attempts = prev.map(|r| r.attempts).unwrap_or(0).saturating_add(1);
+        Self {
+            attempts,
+            next_attempt: now + Self::delay(attempts),
+        }
+    }
+
+    /// Seconds to wait after the `attempts`-th failure.
+    fn delay(attempts: u32) -> u64 {
+        // 60 << 16 is already way past the maximum
+        (RETRY_BASE << attempts.saturating_sub(1).min(16)).min(RETRY_MAX)
+    }
+
+    #[inline(always)]
+    pub fn is_due(&self) -> bool {
+        self.is_due_at(now())
+    }
+
+    #[inline(always)]
+    fn is_due_at(&self, now: u64) -> bool {
+        self.next_attempt <= now
+    }
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+type RetryTable = heed::Database<U64, SerdeBincode<Retry>>;
+
+/// Lease held by the process currently syncing.
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    pid: u32,
+    /// unix timestamp in seconds
+    expires: u64,
+}
+
+/// Lifetime of a sync lease, after which a hung sync is taken over.
+const LEASE_TTL: u64 = 10 * 60;
+
+#[cfg(not(windows))]
+fn process_alive(pid: u32) -> bool {
+    (unsafe { libc::kill(pid as libc::pid_t, 0) }) == 0
+        || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
 #[derive(Debug, Clone)]
 pub struct Database {
     env: heed::Env,
     main: heed::Database<Str, Bytes>,
-    data: Delayed<heed::Database<U64, U64>>,
+    data: heed::Database<U64, U64>,
+    retry: RetryTable,
+    /// when each pending anime was first queued
+    queued: heed::Database<U64, U64>,
+    /// titles seen during sync
+    titles: heed::Database<U64, Str>,
+    /// pending anime whose local episode must be pushed even if lower
+    overrides: heed::Database<U64, Unit>,
+    history: heed::Database<U64, SerdeBincode<history::HistoryEntry>>,
+    /// status and score pulled from the remote list
+    list: heed::Database<U64, SerdeBincode<pull::ListState>>,
+    conflicts: heed::Database<U64, SerdeBincode<Conflict>>,
+    /// list fields other than progress waiting to be synced
+    changes: heed::Database<U64, SerdeBincode<ListChange>>,
+    /// pending anime queued only for their list fields, see
+    /// [`Anime::is_list_only`]
+    list_only: heed::Database<U64, Unit>,
+    /// logins to trackers other than AniList, by name
+    trackers: heed::Database<Str, SerdeBincode<trackers::Credentials>>,
+    /// queues of trackers other than AniList, keyed by `tracker/id`
+    outbox: heed::Database<Str, SerdeBincode<trackers::Outgoing>>,
+    /// ids of AniList anime on other trackers, keyed by `tracker/id`
+    mappings: heed::Database<Str, U64>,
+    /// AniList accounts scrobbled to together, by group name
+    groups: heed::Database<Str, SerdeBincode<Vec<String>>>,
+    /// local reading progress of manga and light novels
+    manga: heed::Database<U64, SerdeBincode<manga::Reading>>,
+    manga_pending: heed::Database<U64, SerdeBincode<manga::PendingReading>>,
+    /// details of anime fetched from AniList
+    media: heed::Database<U64, SerdeBincode<media::CachedMedia>>,
+    /// last rewatch of each anime
+    rewatches: heed::Database<U64, SerdeBincode<rewatches::Rewatch>>,
+    /// lower episodes of anime whose list status was unknown, told apart
+    /// from rewatches on the next sync
+    rewatch_candidates: heed::Database<U64, U64>,
+    /// records that could not be decoded, moved away by `Database::check`
+    corrupt: heed::Database<Str, Bytes>,
 }
 
 impl crate::IsFatal for heed::Error {
     fn is_fatal(&self) -> bool {
         match self {
             heed::Error::EnvAlreadyOpened | heed::Error::Io(_) | heed::Error::Mdb(_) => true,
-            heed::Error::Encoding(_) | heed::Error::Decoding(_) => todo!(),
+            // a single bad record, see `Database::check`
+            heed::Error::Encoding(_) | heed::Error::Decoding(_) => false,
         }
     }
 }
@@ -72,52 +186,192 @@ where
     bincode::serialize(value).map_err(|e| heed::Error::Encoding(Box::new(e)))
 }
 
+/// Layout of the stored records, bumped whenever one of them changes. See
+/// `Database::migrate`.
+const VERSION: u64 = 1;
+
+/// Reads the layout of the stored records. A database without a version is
+/// either new, and gets the current one, or lost it to `Database::check`, and
+/// is assumed to be the oldest.
+fn read_version(main: heed::Database<Str, Bytes>, wtxn: &mut heed::RwTxn) -> Result<u64> {
+    match main.get(wtxn, "version")? {
+        Some(version) => bincode::deserialize::<u64>(version)
+            .context("invalid database version, run `aniscrobble db repair`"),
+        None if main.is_empty(wtxn)? => {
+            main.put(wtxn, "version", &bincode::serialize(&VERSION)?)
+                .context("cannot open database")?;
+            Ok(VERSION)
+        }
+        None => Ok(0),
+    }
+}
+
 impl Database {
     pub fn new() -> Result<Self> {
-        let dirs = directories::ProjectDirs::from("dev", "shurizzle", "aniscrobble").unwrap();
-        let db_file = dirs.cache_dir().join("data.db");
-        std::fs::create_dir_all(&db_file).context("cannot open database")?;
+        let db_file = crate::paths::db_path();
+        crate::paths::migrate_legacy_db(&db_file)?;
+        Self::open(&db_file, true)
+    }
+
+    /// Opens the database even if its version is broken, so that
+    /// `Database::check` can report and quarantine it.
+    pub fn new_unchecked() -> Result<Self> {
+        let db_file = crate::paths::db_path();
+        crate::paths::migrate_legacy_db(&db_file)?;
+        Self::open(&db_file, false)
+    }
+
+    /// Opens the database stored in the `path` directory.
+    pub fn open(path: &Path, check_version: bool) -> Result<Self> {
+        std::fs::create_dir_all(path).context("cannot open database")?;
         let env = unsafe {
             heed::EnvOpenOptions::new()
-                .max_dbs(2)
-                .open(&db_file)
+                .max_dbs(21)
+                .open(path)
                 .context("cannot open database")?
         };
         let main: heed::Database<Str, Bytes>;
+        let version: Result<u64>;
+        let data: heed::Database<U64, U64>;
+        let retry: RetryTable;
+        let queued: heed::Database<U64, U64>;
+        let titles: heed::Database<U64, Str>;
+        let overrides: heed::Database<U64, Unit>;
+        let history: heed::Database<U64, SerdeBincode<history::HistoryEntry>>;
+        let list: heed::Database<U64, SerdeBincode<pull::ListState>>;
+        let conflicts: heed::Database<U64, SerdeBincode<Conflict>>;
+        let changes: heed::Database<U64, SerdeBincode<ListChange>>;
+        let list_only: heed::Database<U64, Unit>;
+        let trackers: heed::Database<Str, SerdeBincode<trackers::Credentials>>;
+        let outbox: heed::Database<Str, SerdeBincode<trackers::Outgoing>>;
+        let mappings: heed::Database<Str, U64>;
+        let groups: heed::Database<Str, SerdeBincode<Vec<String>>>;
+        let manga: heed::Database<U64, SerdeBincode<manga::Reading>>;
+        let manga_pending: heed::Database<U64, SerdeBincode<manga::PendingReading>>;
+        let rewatches: heed::Database<U64, SerdeBincode<rewatches::Rewatch>>;
+        let rewatch_candidates: heed::Database<U64, U64>;
+        let media: heed::Database<U64, SerdeBincode<media::CachedMedia>>;
+        let corrupt: heed::Database<Str, Bytes>;
         {
             let mut wtxn = env.write_txn().context("cannot open database")?;
             main = env
                 .create_database(&mut wtxn, None)
                 .context("cannot open database")?;
-            if let Some(version) = main.get(&wtxn, "version")? {
-                let version =
-                    bincode::deserialize::<u64>(version).context("invalid database version")?;
-                if version != 0 {
-                    bail!("invalid database version");
-                }
-            } else {
-                main.put(&mut wtxn, "version", &bincode::serialize(&0u64)?)
-                    .context("cannot open database")?;
-            }
+            version = read_version(main, &mut wtxn);
+            data = env
+                .create_database(&mut wtxn, Some("data"))
+                .context("cannot open database")?;
+            retry = env
+                .create_database(&mut wtxn, Some("retry"))
+                .context("cannot open database")?;
+            queued = env
+                .create_database(&mut wtxn, Some("queued"))
+                .context("cannot open database")?;
+            titles = env
+                .create_database(&mut wtxn, Some("titles"))
+                .context("cannot open database")?;
+            overrides = env
+                .create_database(&mut wtxn, Some("overrides"))
+                .context("cannot open database")?;
+            history = env
+                .create_database(&mut wtxn, Some("history"))
+                .context("cannot open database")?;
+            list = env
+                .create_database(&mut wtxn, Some("list"))
+                .context("cannot open database")?;
+            conflicts = env
+                .create_database(&mut wtxn, Some("conflicts"))
+                .context("cannot open database")?;
+            changes = env
+                .create_database(&mut wtxn, Some("changes"))
+                .context("cannot open database")?;
+            list_only = env
+                .create_database(&mut wtxn, Some("list_only"))
+                .context("cannot open database")?;
+            trackers = env
+                .create_database(&mut wtxn, Some("trackers"))
+                .context("cannot open database")?;
+            outbox = env
+                .create_database(&mut wtxn, Some("outbox"))
+                .context("cannot open database")?;
+            mappings = env
+                .create_database(&mut wtxn, Some("mappings"))
+                .context("cannot open database")?;
+            groups = env
+                .create_database(&mut wtxn, Some("groups"))
+                .context("cannot open database")?;
+            manga = env
+                .create_database(&mut wtxn, Some("manga"))
+                .context("cannot open database")?;
+            manga_pending = env
+                .create_database(&mut wtxn, Some("manga_pending"))
+                .context("cannot open database")?;
+            rewatches = env
+                .create_database(&mut wtxn, Some("rewatches"))
+                .context("cannot open database")?;
+            rewatch_candidates = env
+                .create_database(&mut wtxn, Some("rewatch_candidates"))
+                .context("cannot open database")?;
+            media = env
+                .create_database(&mut wtxn, Some("media"))
+                .context("cannot open database")?;
+            corrupt = env
+                .create_database(&mut wtxn, Some("corrupt"))
+                .context("cannot open database")?;
             wtxn.commit().context("cannot open database")?;
         }
-        Ok(Self {
+        let db = Self {
             env,
             main,
-            data: Delayed::new(),
-        })
+            data,
+            retry,
+            queued,
+            titles,
+            overrides,
+            history,
+            list,
+            conflicts,
+            changes,
+            list_only,
+            trackers,
+            outbox,
+            mappings,
+            groups,
+            manga,
+            manga_pending,
+            rewatches,
+            rewatch_candidates,
+            media,
+            corrupt,
+        };
+        match version {
+            Ok(version) if version < VERSION => db.migrate(version)?,
+            Ok(version) if version > VERSION && check_version => {
+                bail!("database version {version} is newer than this aniscrobble")
+            }
+            Err(err) if check_version => return Err(err),
+            // left to `Database::check`
+            _ => (),
+        }
+        Ok(db)
     }
 
-    fn data(&self, wtxn: Option<&mut heed::RwTxn>) -> heed::Result<&heed::Database<U64, U64>> {
-        if let Some(wtxn) = wtxn {
-            self.data
-                .get(|| self.env.create_database(wtxn, Some("data")))
-        } else {
-            self.data.get(|| {
-                let mut wtxn = self.env.write_txn()?;
-                self.env.create_database(&mut wtxn, Some("data"))
-            })
+    /// Earliest scheduled retry, if any anime is waiting for one.
+    pub fn next_retry(&self) -> heed::Result<Option<u64>> {
+        let rtxn = self.env.read_txn()?;
+        let mut next = None;
+        for entry in self.retry.iter(&rtxn)? {
+            let (_, entry) = entry?;
+            next = Some(next.map_or(entry.next_attempt, |n: u64| n.min(entry.next_attempt)));
         }
+        Ok([
+            next,
+            self.outbox_next_retry(&rtxn)?,
+            self.manga_next_retry(&rtxn)?,
+        ]
+        .into_iter()
+        .flatten()
+        .min())
     }
 
     pub fn login(&self) -> heed::Result<Option<User>> {
@@ -139,150 +393,313 @@ impl Database {
     pub fn delete_login(&self) -> heed::Result<()> {
         let mut wtxn = self.env.write_txn()?;
         self.main.delete(&mut wtxn, "login")?;
+        // it belongs to the user
+        self.main.delete(&mut wtxn, "score_format")?;
         wtxn.commit()?;
         Ok(())
     }
 
-    pub fn scrobble(&self, id: u64, episode: u64) -> heed::Result<()> {
+    /// Queues `episode` for `id`, also for the extra AniList accounts in
+    /// `accounts`. Episodes not above the stored one are ignored, unless
+    /// `force` is set: then the episode is pushed to AniList even if it
+    /// lowers the remote progress. `change` is queued either way.
+    ///
+    /// A lower episode of a completed anime starts a rewatch instead, and
+    /// `true` is returned. If the list status is not known yet, the anime is
+    /// queued so that the next sync can tell, see
+    /// [`Anime::rewatch_candidate`].
+    pub fn scrobble(
+        &self,
+        id: u64,
+        episode: u64,
+        force: bool,
+        change: Option<&ListChange>,
+        accounts: &[String],
+    ) -> heed::Result<bool> {
         let mut wtxn = self.env.write_txn()?;
-        let data = self.data(Some(&mut wtxn))?;
-        if data.get(&wtxn, &id)?.map(|ep| ep < episode).unwrap_or(true) {
-            let mut pending = self
-                .main
-                .get(&wtxn, "pending")?
-                .map(bincode_deserialize::<Vec<u64>>)
-                .transpose()?
-                .unwrap_or_default();
-            if let Err(i) = pending.binary_search(&id) {
-                pending.insert(i, id);
-                self.main
-                    .put(&mut wtxn, "pending", &bincode_serialize(&pending)?)?;
+        let previous = self.data.get(&wtxn, &id)?;
+        let lower = !force && previous.is_some_and(|ep| episode < ep);
+        let completed = if lower {
+            self.is_completed(&wtxn, id)?
+        } else {
+            Some(false)
+        };
+        let rewatch = completed == Some(true);
+        let advanced = force || rewatch || previous.map(|ep| ep < episode).unwrap_or(true);
+        if completed.is_none() {
+            self.rewatch_candidates.put(&mut wtxn, &id, &episode)?;
+        } else if !advanced && change.is_none() {
+            return Ok(false);
+        }
+        if rewatch {
+            self.start_rewatch(&mut wtxn, id)?;
+        }
+        match change {
+            // nothing scrobbled, like `Database::queue_change`
+            Some(change) if !advanced && completed.is_some() => {
+                self.enqueue_change(&mut wtxn, id, change)?;
             }
-            data.put(&mut wtxn, &id, &episode)?;
+            change => {
+                if let Some(change) = change {
+                    self.merge_change(&mut wtxn, id, change)?;
+                }
+                if advanced {
+                    self.data.put(&mut wtxn, &id, &episode)?;
+                    self.rewatch_candidates.delete(&mut wtxn, &id)?;
+                    // the lower episode of a rewatch is pushed like a forced one
+                    if force || rewatch {
+                        self.overrides.put(&mut wtxn, &id, &())?;
+                    }
+                }
+                self.enqueue(&mut wtxn, id)?;
+            }
+        }
+        self.dispatch_to(&mut wtxn, id, accounts)?;
+        if advanced {
+            self.record_history(&mut wtxn, id, episode, previous)?;
+        }
+        wtxn.commit()?;
+        Ok(rewatch)
+    }
+
+    /// Takes the sync lease. If another process is already syncing, asks it
+    /// to run once more and returns `None`.
+    pub fn lock_sync(&self) -> heed::Result<Option<SyncLock<'_>>> {
+        let pid = std::process::id();
+        let mut wtxn = self.env.write_txn()?;
+        if let Some(lease) = self
+            .main
+            .get(&wtxn, "sync_lock")?
+            .map(bincode_deserialize::<Lease>)
+            .transpose()?
+            && lease.expires > now()
+            && process_alive(lease.pid)
+        {
+            self.main
+                .put(&mut wtxn, "sync_requested", &bincode_serialize(&true)?)?;
             wtxn.commit()?;
+            return Ok(None);
         }
-        Ok(())
+        self.main.put(
+            &mut wtxn,
+            "sync_lock",
+            &bincode_serialize(&Lease {
+                pid,
+                expires: now() + LEASE_TTL,
+            })?,
+        )?;
+        self.main.delete(&mut wtxn, "sync_requested")?;
+        wtxn.commit()?;
+        Ok(Some(SyncLock {
+            db: self,
+            pid,
+            held: true,
+        }))
     }
 
-    pub fn sync(&self) -> heed::Result<SyncContext> {
-        let wtxn = self.env.write_txn()?;
-        let pending = self
+    pub fn pending_len(&self) -> heed::Result<usize> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self
             .main
-            .get(&wtxn, "pending")?
+            .get(&rtxn, "pending")?
             .map(bincode_deserialize::<Vec<u64>>)
             .transpose()?
-            .unwrap_or_default();
+            .map(|pending| pending.len())
+            .unwrap_or(0))
+    }
+
+    fn pending(&self, txn: &heed::RoTxn) -> heed::Result<Vec<u64>> {
+        Ok(self
+            .main
+            .get(txn, "pending")?
+            .map(bincode_deserialize::<Vec<u64>>)
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    /// Starts a sync of the pending queue. Anime waiting for a retry are
+    /// skipped unless `force` is set.
+    ///
+    /// The queue is snapshotted in a read transaction, so that no write
+    /// transaction is held while talking to AniList: results are applied one
+    /// anime at a time by [`Anime::update`] and [`Anime::failed`].
+    pub fn sync(&self, force: bool) -> heed::Result<SyncContext<'_>> {
+        let mut pending = Vec::new();
+        let mut orphans = Vec::new();
+        {
+            let rtxn = self.env.read_txn()?;
+            let mut queue = Vec::new();
+            for id in self.pending(&rtxn)? {
+                queue.push((id, self.data.get(&rtxn, &id)?, self.retry.get(&rtxn, &id)?));
+            }
+            let plan = SyncPlan::new(queue, force, now());
+            for (id, episode) in plan.sync {
+                pending.push(Anime {
+                    db: self,
+                    tracker: None,
+                    id,
+                    episode,
+                    is_override: self.overrides.get(&rtxn, &id)?.is_some(),
+                    is_list_only: self.list_only.get(&rtxn, &id)?.is_some(),
+                    queued_at: self.queued.get(&rtxn, &id)?,
+                    change: self.changes.get(&rtxn, &id)?,
+                    rewatch_candidate: self.rewatch_candidates.get(&rtxn, &id)?,
+                });
+            }
+            for id in plan.orphans {
+                orphans.push((id, self.changes.get(&rtxn, &id)?));
+            }
+        }
+
+        if !orphans.is_empty() {
+            self.drop_orphans(&orphans)?;
+        }
+
         Ok(SyncContext {
-            changed: false,
-            txn: ManuallyDrop::new(wtxn),
-            pending,
-            db: self,
-            idx: 0,
+            pending: pending.into_iter(),
         })
     }
-}
-
-pub struct SyncContext<'a> {
-    changed: bool,
-    txn: ManuallyDrop<heed::RwTxn<'a>>,
-    pending: Vec<u64>,
-    db: &'a Database,
-    idx: usize,
-}
 
-impl std::fmt::Debug for SyncContext<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("SyncContext")
-            .field("changed", &self.changed)
-            .field("pending", &self.pending)
-            .field("db", &self.db)
-            .field("idx", &self.idx)
-            .finish()
+    /// Dequeues the pending anime without an episode, as snapshotted with
+    /// their list change by [`Database::sync`], unless scrobbled or rated
+    /// since.
+    fn drop_orphans(&self, orphans: &[(u64, Option<ListChange>)]) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        for (id, change) in orphans {
+            if self.data.get(&wtxn, id)?.is_none() && self.changes.get(&wtxn, id)? == *change {
+                self.dequeue(&mut wtxn, *id)?;
+            }
+        }
+        wtxn.commit()
     }
 }
 
-pub struct Anime<'a> {
-    ctx: &'a mut SyncContext<'a>,
-    id: u64,
-    episode: u64,
+/// What a sync does with each anime of the pending queue, kept apart from the
+/// database so that it can be tested on its own.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SyncPlan {
+    /// Anime to sync with their episode, in queue order.
+    sync: Vec<(u64, u64)>,
+    /// Anime without an episode, left in the queue by a dropped scrobble.
+    orphans: Vec<u64>,
 }
 
-impl std::fmt::Debug for Anime<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Anime")
-            .field("id", &self.id)
-            .field("episode", &self.episode)
-            .finish()
+impl SyncPlan {
+    /// Plans a sync at `now` of the `(id, episode, retry)` of the queue.
+    /// Anime waiting for a retry are skipped unless `force` is set, orphans
+    /// are dropped either way.
+    fn new(
+        queue: impl IntoIterator<Item = (u64, Option<u64>, Option<Retry>)>,
+        force: bool,
+        now: u64,
+    ) -> Self {
+        let mut plan = Self::default();
+        for (id, episode, retry) in queue {
+            match episode {
+                None => plan.orphans.push(id),
+                Some(episode) if force || retry.is_none_or(|retry| retry.is_due_at(now)) => {
+                    plan.sync.push((id, episode))
+                }
+                Some(_) => (),
+            }
+        }
+        plan
     }
 }
 
-impl Drop for Anime<'_> {
-    fn drop(&mut self) {
-        self.ctx.idx += 1;
-    }
+#[derive(Debug)]
+pub struct SyncLock<'a> {
+    db: &'a Database,
+    pid: u32,
+    held: bool,
 }
 
-impl SyncContext<'_> {
-    pub fn next(&mut self) -> Option<heed::Result<Anime>> {
-        loop {
-            let id = *self.pending.get(self.idx)?;
-            let episode = self
-                .db
-                .data(Some(&mut self.txn))
-                .and_then(|data| data.get(&self.txn, &id));
-            let episode = match episode {
-                Ok(e) => e,
-                Err(err) => return Some(Err(err)),
-            };
-            if let Some(episode) = episode {
-                return Some(Ok(Anime {
-                    ctx: unsafe {
-                        core::mem::transmute::<&mut SyncContext<'_>, &mut SyncContext<'_>>(self)
-                    },
-                    id,
-                    episode,
-                }));
-            }
-            self.pending.remove(self.idx);
-            self.changed = true;
-        }
+impl SyncLock<'_> {
+    /// Extends the lease, so that long syncs are not taken over.
+    pub fn renew(&self) -> heed::Result<()> {
+        let mut wtxn = self.db.env.write_txn()?;
+        self.db.main.put(
+            &mut wtxn,
+            "sync_lock",
+            &bincode_serialize(&Lease {
+                pid: self.pid,
+                expires: now() + LEASE_TTL,
+            })?,
+        )?;
+        wtxn.commit()
     }
 
-    pub fn commit(self) -> heed::Result<()> {
-        let changed = self.changed;
-        let pending = unsafe { std::ptr::read_volatile(&self.pending) };
-        let mut txn = unsafe { std::ptr::read_volatile(&*self.txn) };
-        let db = unsafe { std::ptr::read_volatile(&self.db) };
-        core::mem::forget(self);
-
-        if changed {
-            match bincode_serialize(&pending)
-                .and_then(|bincode| db.main.put(&mut txn, "pending", &bincode))
-            {
-                Ok(()) => txn.commit(),
-                Err(err) => {
-                    _ = txn.commit();
-                    Err(err)
-                }
-            }
+    /// Releases the lease, unless another sync was requested while this one
+    /// was running: in that case the lease is renewed and `true` is returned,
+    /// so that the caller can run again.
+    pub fn finish(&mut self) -> heed::Result<bool> {
+        let db = self.db;
+        let mut wtxn = db.env.write_txn()?;
+        let requested = db
+            .main
+            .get(&wtxn, "sync_requested")?
+            .map(bincode_deserialize::<bool>)
+            .transpose()?
+            .unwrap_or(false);
+        if requested {
+            db.main.delete(&mut wtxn, "sync_requested")?;
+            db.main.put(
+                &mut wtxn,
+                "sync_lock",
+                &bincode_serialize(&Lease {
+                    pid: self.pid,
+                    expires: now() + LEASE_TTL,
+                })?,
+            )?;
         } else {
-            txn.commit()
+            db.main.delete(&mut wtxn, "sync_lock")?;
         }
+        wtxn.commit()?;
+        self.held = requested;
+        Ok(requested)
     }
 }
 
-impl Drop for SyncContext<'_> {
+impl Drop for SyncLock<'_> {
     fn drop(&mut self) {
-        let mut txn = unsafe { std::ptr::read_volatile(&*self.txn) };
-        if self.changed {
-            if let Ok(pending) = bincode::serialize(&self.pending) {
-                _ = self.db.main.put(&mut txn, "pending", &pending);
-            }
+        if !self.held {
+            return;
         }
-        _ = txn.commit();
+        if let Ok(mut wtxn) = self.db.env.write_txn() {
+            _ = self.db.main.delete(&mut wtxn, "sync_lock");
+            _ = wtxn.commit();
+        }
+    }
+}
+
+/// Owned snapshot of the pending queue, yielding the anime to sync.
+#[derive(Debug)]
+pub struct SyncContext<'a> {
+    pending: std::vec::IntoIter<Anime<'a>>,
+}
+
+impl<'a> Iterator for SyncContext<'a> {
+    type Item = Anime<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pending.next()
     }
 }
 
+#[derive(Debug)]
+pub struct Anime<'a> {
+    db: &'a Database,
+    /// `None` for AniList
+    tracker: Option<String>,
+    id: u64,
+    episode: u64,
+    is_override: bool,
+    is_list_only: bool,
+    queued_at: Option<u64>,
+    change: Option<ListChange>,
+    rewatch_candidate: Option<u64>,
+}
+
 impl Anime<'_> {
     #[inline(always)]
     pub fn id(&self) -> u64 {
@@ -294,21 +711,437 @@ impl Anime<'_> {
         self.episode
     }
 
-    pub fn update(self, episode: u64) -> heed::Result<()> {
-        let ctx = unsafe { core::ptr::read_volatile(&self.ctx) };
-        let id = self.id;
-        let old_episode = self.episode;
-        std::mem::forget(self);
-
-        let pid = ctx.pending.binary_search(&id).expect("pending episode");
-        debug_assert!(episode >= old_episode);
-        if episode > old_episode {
-            ctx.db
-                .data(Some(&mut ctx.txn))?
-                .put(&mut ctx.txn, &id, &episode)?;
+    /// Whether the local episode must replace the remote one even if lower.
+    #[inline(always)]
+    pub fn is_override(&self) -> bool {
+        self.is_override
+    }
+
+    /// Whether only the list fields are queued: nothing was scrobbled since
+    /// the last sync, so the local episode is not pushed and cannot
+    /// conflict with the remote one.
+    #[inline(always)]
+    pub fn is_list_only(&self) -> bool {
+        self.is_list_only
+    }
+
+    #[inline(always)]
+    pub fn queued_at(&self) -> Option<u64> {
+        self.queued_at
+    }
+
+    /// List fields to save along with the episode.
+    #[inline(always)]
+    pub fn change(&self) -> Option<&ListChange> {
+        self.change.as_ref()
+    }
+
+    /// Lower episode scrobbled while the list status was unknown: if the
+    /// anime turns out to be completed, call [`Anime::start_rewatch`],
+    /// otherwise [`Anime::drop_rewatch_candidate`].
+    #[inline(always)]
+    pub fn rewatch_candidate(&self) -> Option<u64> {
+        self.rewatch_candidate
+    }
+
+    /// Starts a rewatch from the candidate episode, pushed like a forced one.
+    pub fn start_rewatch(&mut self) -> heed::Result<()> {
+        let Some(episode) = self.rewatch_candidate.take() else {
+            return Ok(());
+        };
+        let db = self.db;
+        let mut wtxn = db.env.write_txn()?;
+        // scrobbled again since the sync started
+        if db.data.get(&wtxn, &self.id)? != Some(self.episode)
+            || db.rewatch_candidates.get(&wtxn, &self.id)? != Some(episode)
+        {
+            return Ok(());
         }
-        ctx.pending.remove(pid);
-        ctx.changed = true;
+        db.rewatch_candidates.delete(&mut wtxn, &self.id)?;
+        db.start_rewatch(&mut wtxn, self.id)?;
+        db.data.put(&mut wtxn, &self.id, &episode)?;
+        db.overrides.put(&mut wtxn, &self.id, &())?;
+        db.list_only.delete(&mut wtxn, &self.id)?;
+        db.dispatch(&mut wtxn, self.id)?;
+        db.record_history(&mut wtxn, self.id, episode, Some(self.episode))?;
+        wtxn.commit()?;
+        self.episode = episode;
+        self.is_override = true;
+        self.is_list_only = false;
         Ok(())
     }
+
+    /// Forgets the candidate episode, as the anime is not being rewatched.
+    pub fn drop_rewatch_candidate(&mut self) -> heed::Result<()> {
+        let Some(episode) = self.rewatch_candidate.take() else {
+            return Ok(());
+        };
+        let mut wtxn = self.db.env.write_txn()?;
+        if self.db.rewatch_candidates.get(&wtxn, &self.id)? == Some(episode) {
+            self.db.rewatch_candidates.delete(&mut wtxn, &self.id)?;
+        }
+        wtxn.commit()
+    }
+
+    /// Marks the anime as synced at `episode`.
+    ///
+    /// If it was scrobbled or rated again since the sync started, the stored
+    /// episode or list change no longer matches the snapshot: the newer ones
+    /// are kept and the anime stays pending.
+    pub fn update(self, episode: u64) -> heed::Result<()> {
+        let db = self.db;
+        if let Some(tracker) = &self.tracker {
+            return db.outbox_synced(tracker, self.id, self.episode, self.change.as_ref());
+        }
+        let mut wtxn = db.env.write_txn()?;
+        if db.data.get(&wtxn, &self.id)? != Some(self.episode)
+            || db.changes.get(&wtxn, &self.id)? != self.change
+        {
+            return Ok(());
+        }
+        if episode != self.episode {
+            db.data.put(&mut wtxn, &self.id, &episode)?;
+        }
+        db.dequeue(&mut wtxn, self.id)?;
+        wtxn.commit()
+    }
+
+    /// Records a failed sync attempt, keeping the anime pending until its
+    /// next retry.
+    pub fn failed(self) -> heed::Result<Retry> {
+        let db = self.db;
+        if let Some(tracker) = &self.tracker {
+            return db.outbox_failed(tracker, self.id);
+        }
+        let mut wtxn = db.env.write_txn()?;
+        let entry = Retry::failed(db.retry.get(&wtxn, &self.id)?);
+        db.retry.put(&mut wtxn, &self.id, &entry)?;
+        wtxn.commit()?;
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RETRY_BASE, RETRY_MAX, Retry, SyncPlan, testing::TempDatabase};
+    use crate::api::{ListChange, MediaListStatus};
+
+    #[test]
+    fn retry_delay_doubles_up_to_max() {
+        assert_eq!(Retry::delay(1), RETRY_BASE);
+        assert_eq!(Retry::delay(2), RETRY_BASE * 2);
+        assert_eq!(Retry::delay(3), RETRY_BASE * 4);
+        assert_eq!(Retry::delay(10), RETRY_MAX);
+        // shifts past the width of u64 must not wrap
+        for attempts in 59..=64 {
+            assert_eq!(Retry::delay(attempts), RETRY_MAX);
+        }
+        assert_eq!(Retry::delay(u32::MAX), RETRY_MAX);
+    }
+
+    #[test]
+    fn retry_backs_off_from_the_failure() {
+        let first = Retry::failed_at(None, 1000);
+        assert_eq!(first.attempts, 1);
+        assert_eq!(first.next_attempt, 1000 + RETRY_BASE);
+        assert!(!first.is_due_at(1000 + RETRY_BASE - 1));
+        assert!(first.is_due_at(1000 + RETRY_BASE));
+
+        let second = Retry::failed_at(Some(first), 2000);
+        assert_eq!(second.attempts, 2);
+        assert_eq!(second.next_attempt, 2000 + RETRY_BASE * 2);
+    }
+
+    #[test]
+    fn sync_plan_keeps_queue_order() {
+        let plan = SyncPlan::new(
+            [(3, Some(1), None), (1, Some(7), None), (2, Some(4), None)],
+            false,
+            0,
+        );
+        assert_eq!(plan.sync, [(3, 1), (1, 7), (2, 4)]);
+        assert!(plan.orphans.is_empty());
+    }
+
+    #[test]
+    fn sync_plan_skips_waiting_anime_unless_forced() {
+        let waiting = Retry {
+            attempts: 1,
+            next_attempt: 100,
+        };
+        let due = Retry {
+            attempts: 3,
+            next_attempt: 50,
+        };
+        let queue = [
+            (1, Some(5), Some(waiting)),
+            (2, None, Some(waiting)),
+            (3, Some(2), Some(due)),
+            (4, None, None),
+        ];
+        assert_eq!(
+            SyncPlan::new(queue, false, 50),
+            SyncPlan {
+                sync: vec![(3, 2)],
+                orphans: vec![2, 4],
+            }
+        );
+        assert_eq!(
+            SyncPlan::new(queue, true, 50),
+            SyncPlan {
+                sync: vec![(1, 5), (3, 2)],
+                orphans: vec![2, 4],
+            }
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn update_keeps_newer_scrobble() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+
+        let mut sync = db.sync(false).unwrap();
+        let anime = sync.next().unwrap();
+        assert_eq!(anime.episode(), 5);
+
+        // scrobbled while the sync talks to AniList
+        db.scrobble(1, 7, false, None, &[]).unwrap();
+        anime.update(5).unwrap();
+
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(db.data.get(&rtxn, &1).unwrap(), Some(7));
+        assert_eq!(db.pending(&rtxn).unwrap(), [1]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn update_keeps_change_queued_during_sync() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+
+        let anime = db.sync(false).unwrap().next().unwrap();
+        // rated while the sync talks to AniList
+        let score = ListChange {
+            score: Some(8.0),
+            ..Default::default()
+        };
+        db.queue_change(1, &score).unwrap();
+        anime.update(5).unwrap();
+        assert_eq!(db.pending_len().unwrap(), 1);
+
+        let anime = db.sync(false).unwrap().next().unwrap();
+        assert_eq!(anime.change(), Some(&score));
+        // and again, along with the same episode
+        let notes = ListChange {
+            notes: Some("notes".to_string()),
+            ..Default::default()
+        };
+        db.scrobble(1, 5, false, Some(&notes), &[]).unwrap();
+        anime.update(5).unwrap();
+        assert_eq!(db.pending_len().unwrap(), 1);
+
+        let anime = db.sync(false).unwrap().next().unwrap();
+        assert_eq!(anime.change(), Some(&score.clone().merge(notes)));
+        anime.update(5).unwrap();
+        assert_eq!(db.pending_len().unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn update_dequeues_synced_anime() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+
+        let anime = db.sync(false).unwrap().next().unwrap();
+        anime.update(5).unwrap();
+
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(db.data.get(&rtxn, &1).unwrap(), Some(5));
+        assert!(db.pending(&rtxn).unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn sync_yields_every_pending_anime() {
+        let db = TempDatabase::new();
+        db.scrobble(3, 1, false, None, &[]).unwrap();
+        db.scrobble(1, 2, false, None, &[]).unwrap();
+        db.scrobble(2, 3, false, None, &[]).unwrap();
+
+        let synced: Vec<_> = db
+            .sync(false)
+            .unwrap()
+            .map(|anime| (anime.id(), anime.episode()))
+            .collect();
+        assert_eq!(synced, [(1, 2), (2, 3), (3, 1)]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn dropped_anime_stays_pending() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+
+        // e.g. the sync was interrupted
+        drop(db.sync(false).unwrap());
+        let anime = db.sync(false).unwrap().next().unwrap();
+        drop(anime);
+
+        assert_eq!(db.pending_len().unwrap(), 1);
+        let anime = db.sync(false).unwrap().next().unwrap();
+        assert_eq!((anime.id(), anime.episode()), (1, 5));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn failed_anime_waits_for_retry() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.scrobble(2, 5, false, None, &[]).unwrap();
+
+        let mut sync = db.sync(false).unwrap();
+        let retry = sync.next().unwrap().failed().unwrap();
+        assert_eq!(retry.attempts, 1);
+        assert!(!retry.is_due());
+        sync.next().unwrap().update(5).unwrap();
+        assert!(sync.next().is_none());
+
+        assert_eq!(db.pending_len().unwrap(), 1);
+        assert!(db.sync(false).unwrap().next().is_none());
+        let anime = db.sync(true).unwrap().next().unwrap();
+        assert_eq!(anime.id(), 1);
+        let retry = anime.failed().unwrap();
+        assert_eq!(retry.attempts, 2);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn sync_drops_orphans() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        {
+            let mut wtxn = db.env.write_txn().unwrap();
+            db.data.delete(&mut wtxn, &1).unwrap();
+            wtxn.commit().unwrap();
+        }
+
+        assert!(db.sync(false).unwrap().next().is_none());
+        assert_eq!(db.pending_len().unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn orphans_scrobbled_during_sync_stay_pending() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.scrobble(2, 5, false, None, &[]).unwrap();
+        {
+            let mut wtxn = db.env.write_txn().unwrap();
+            db.data.delete(&mut wtxn, &1).unwrap();
+            db.data.delete(&mut wtxn, &2).unwrap();
+            wtxn.commit().unwrap();
+        }
+
+        // as snapshotted by `sync`, then scrobbled and rated before the
+        // orphans are dropped
+        let orphans = [(1, None), (2, None)];
+        db.scrobble(1, 6, false, None, &[]).unwrap();
+        db.queue_change(
+            2,
+            &ListChange {
+                score: Some(8.0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.drop_orphans(&orphans).unwrap();
+
+        let pending: Vec<_> = db
+            .sync(false)
+            .unwrap()
+            .map(|anime| (anime.id(), anime.episode()))
+            .collect();
+        assert_eq!(pending, [(1, 6), (2, 0)]);
+    }
+
+    /// Scrobbles episode 12 of anime 1 and syncs it.
+    fn watched(db: &TempDatabase) {
+        db.scrobble(1, 12, false, None, &[]).unwrap();
+        db.sync(false).unwrap().next().unwrap().update(12).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn lower_episode_of_unknown_status_waits_for_sync() {
+        let db = TempDatabase::new();
+        watched(&db);
+
+        assert!(!db.scrobble(1, 3, false, None, &[]).unwrap());
+        assert_eq!(db.pending_len().unwrap(), 1);
+        let mut anime = db.sync(false).unwrap().next().unwrap();
+        assert_eq!(anime.episode(), 12);
+        assert_eq!(anime.rewatch_candidate(), Some(3));
+
+        // AniList says it is completed
+        anime.start_rewatch().unwrap();
+        assert_eq!(anime.episode(), 3);
+        assert!(anime.is_override());
+        anime.update(3).unwrap();
+
+        assert_eq!(db.pending_len().unwrap(), 0);
+        assert_eq!(db.rewatch(1).unwrap().unwrap().cycle, 1);
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(db.data.get(&rtxn, &1).unwrap(), Some(3));
+        assert_eq!(db.rewatch_candidates.get(&rtxn, &1).unwrap(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn lower_episode_dropped_if_not_completed() {
+        let db = TempDatabase::new();
+        watched(&db);
+
+        db.scrobble(1, 3, false, None, &[]).unwrap();
+        let mut anime = db.sync(false).unwrap().next().unwrap();
+        anime.drop_rewatch_candidate().unwrap();
+        anime.update(12).unwrap();
+
+        assert_eq!(db.pending_len().unwrap(), 0);
+        assert!(db.rewatch(1).unwrap().is_none());
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(db.data.get(&rtxn, &1).unwrap(), Some(12));
+        assert_eq!(db.rewatch_candidates.get(&rtxn, &1).unwrap(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn lower_episode_of_known_status() {
+        let db = TempDatabase::new();
+        watched(&db);
+
+        db.set_list_status(1, MediaListStatus::Current).unwrap();
+        assert!(!db.scrobble(1, 3, false, None, &[]).unwrap());
+        assert_eq!(db.pending_len().unwrap(), 0);
+
+        db.set_list_status(1, MediaListStatus::Completed).unwrap();
+        assert!(db.scrobble(1, 3, false, None, &[]).unwrap());
+        let anime = db.sync(false).unwrap().next().unwrap();
+        assert_eq!(anime.episode(), 3);
+        assert!(anime.is_override());
+        assert_eq!(anime.rewatch_candidate(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn higher_episode_clears_candidate() {
+        let db = TempDatabase::new();
+        watched(&db);
+
+        db.scrobble(1, 3, false, None, &[]).unwrap();
+        db.scrobble(1, 13, false, None, &[]).unwrap();
+        let anime = db.sync(false).unwrap().next().unwrap();
+        assert_eq!(anime.episode(), 13);
+        assert_eq!(anime.rewatch_candidate(), None);
+    }
 }
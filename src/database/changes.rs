@@ -0,0 +1,137 @@
+use super::{Database, bincode_deserialize, bincode_serialize};
+use crate::api::{ListChange, ScoreFormat};
+
+/// An anime to queue with its list fields, as read from another service.
+#[derive(Debug)]
+pub struct QueuedChange {
+    pub id: u64,
+    pub title: Option<String>,
+    pub episode: u64,
+    pub change: ListChange,
+}
+
+impl Database {
+    /// Queues `entries` for the next sync. Local episodes only move forward,
+    /// the other fields replace any change already queued.
+    pub fn queue_changes(&self, entries: &[QueuedChange]) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        for entry in entries {
+            let episode = self
+                .data
+                .get(&wtxn, &entry.id)?
+                .map_or(entry.episode, |ep| ep.max(entry.episode));
+            self.data.put(&mut wtxn, &entry.id, &episode)?;
+            self.changes.put(&mut wtxn, &entry.id, &entry.change)?;
+            if let Some(title) = &entry.title {
+                self.titles.put(&mut wtxn, &entry.id, title)?;
+            }
+            self.enqueue(&mut wtxn, entry.id)?;
+        }
+        wtxn.commit()
+    }
+
+    /// Queues `change` for `id`, even if never scrobbled, on top of any
+    /// change already queued.
+    pub fn queue_change(&self, id: u64, change: &ListChange) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.enqueue_change(&mut wtxn, id, change)?;
+        wtxn.commit()
+    }
+
+    /// Queues `change` alone: unless a scrobble of `id` is already pending,
+    /// only the list fields are pushed, see [`super::Anime::is_list_only`].
+    pub(super) fn enqueue_change(
+        &self,
+        wtxn: &mut heed::RwTxn,
+        id: u64,
+        change: &ListChange,
+    ) -> heed::Result<()> {
+        let scrobbled = self.pending(wtxn)?.binary_search(&id).is_ok()
+            && self.list_only.get(wtxn, &id)?.is_none();
+        if self.data.get(wtxn, &id)?.is_none() {
+            self.data.put(wtxn, &id, &0)?;
+        }
+        self.merge_change(wtxn, id, change)?;
+        if !scrobbled {
+            self.list_only.put(wtxn, &id, &())?;
+        }
+        self.push_pending(wtxn, id)
+    }
+
+    pub(super) fn merge_change(
+        &self,
+        wtxn: &mut heed::RwTxn,
+        id: u64,
+        change: &ListChange,
+    ) -> heed::Result<()> {
+        let merged = self
+            .changes
+            .get(wtxn, &id)?
+            .unwrap_or_default()
+            .merge(change.clone());
+        self.changes.put(wtxn, &id, &merged)
+    }
+
+    /// Score format of the logged in user, as cached by
+    /// [`Database::set_score_format`].
+    pub fn score_format(&self) -> heed::Result<Option<ScoreFormat>> {
+        let rtxn = self.env.read_txn()?;
+        self.main
+            .get(&rtxn, "score_format")?
+            .map(bincode_deserialize::<ScoreFormat>)
+            .transpose()
+    }
+
+    pub fn set_score_format(&self, format: ScoreFormat) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.main
+            .put(&mut wtxn, "score_format", &bincode_serialize(&format)?)?;
+        wtxn.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::ListChange, database::testing::TempDatabase};
+
+    fn score() -> ListChange {
+        ListChange {
+            score: Some(8.0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn change_alone_is_list_only() {
+        let db = TempDatabase::new();
+        db.queue_change(1, &score()).unwrap();
+        // rated again before the sync, and for an episode already synced
+        db.queue_change(1, &score()).unwrap();
+        db.scrobble(2, 5, false, None, &[]).unwrap();
+        db.sync(false).unwrap().nth(1).unwrap().update(5).unwrap();
+        db.scrobble(2, 5, false, Some(&score()), &[]).unwrap();
+
+        let list_only: Vec<_> = db
+            .sync(false)
+            .unwrap()
+            .map(|anime| (anime.id(), anime.is_list_only()))
+            .collect();
+        assert_eq!(list_only, [(1, true), (2, true)]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn change_with_scrobble_is_not_list_only() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.queue_change(1, &score()).unwrap();
+        db.queue_change(2, &score()).unwrap();
+        db.scrobble(2, 3, false, None, &[]).unwrap();
+
+        for anime in db.sync(false).unwrap() {
+            assert!(!anime.is_list_only(), "{}", anime.id());
+            assert_eq!(anime.change().and_then(|change| change.score), Some(8.0));
+        }
+    }
+}
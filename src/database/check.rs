@@ -0,0 +1,367 @@
+use heed::types::{Bytes, SerdeBincode, Str, Unit};
+
+use super::{Database, Lease, U64, User, bincode_serialize, now};
+use crate::{api::ScoreFormat, conflict::Policy};
+
+#[derive(Debug)]
+pub struct Issue {
+    /// table and key of the record, like `main/login` or `data/1234`
+    pub record: String,
+    pub problem: String,
+    pub fixed: bool,
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.record, self.problem)?;
+        if self.fixed {
+            f.write_str(" (fixed)")?;
+        }
+        Ok(())
+    }
+}
+
+const TABLES: [&[u8]; 20] = [
+    b"data",
+    b"retry",
+    b"queued",
+    b"titles",
+    b"overrides",
+    b"history",
+    b"list",
+    b"conflicts",
+    b"changes",
+    b"list_only",
+    b"trackers",
+    b"outbox",
+    b"mappings",
+    b"groups",
+    b"manga",
+    b"manga_pending",
+    b"rewatches",
+    b"rewatch_candidates",
+    b"media",
+    b"corrupt",
+];
+
+/// Validation of the records stored with a codec.
+trait Codec {
+    fn is_valid(bytes: &[u8]) -> bool;
+
+    /// Key as shown in issues.
+    fn show(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+impl Codec for U64 {
+    fn is_valid(bytes: &[u8]) -> bool {
+        bytes.len() == 8
+    }
+
+    fn show(bytes: &[u8]) -> String {
+        match <[u8; 8]>::try_from(bytes) {
+            Ok(bytes) => u64::from_le_bytes(bytes).to_string(),
+            Err(_) => bytes.iter().map(|b| format!("{b:02x}")).collect(),
+        }
+    }
+}
+
+impl Codec for Str {
+    fn is_valid(bytes: &[u8]) -> bool {
+        std::str::from_utf8(bytes).is_ok()
+    }
+
+    fn show(bytes: &[u8]) -> String {
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+impl Codec for Unit {
+    fn is_valid(bytes: &[u8]) -> bool {
+        bytes.is_empty()
+    }
+}
+
+impl<T: serde::de::DeserializeOwned> Codec for SerdeBincode<T> {
+    fn is_valid(bytes: &[u8]) -> bool {
+        is_valid::<T>(bytes)
+    }
+}
+
+fn is_valid<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> bool {
+    bincode::deserialize::<T>(bytes).is_ok()
+}
+
+impl Database {
+    /// Validates every record in `main` and in the tables. With `repair`,
+    /// undecodable records are moved to the `corrupt` table and the pending
+    /// queue is rebuilt.
+    pub fn check(&self, repair: bool) -> heed::Result<Vec<Issue>> {
+        let mut issues = Vec::new();
+        let mut wtxn = self.env.write_txn()?;
+        let mut rebuild_pending = false;
+
+        let main = self.main.remap_key_type::<Bytes>();
+        let mut quarantine = Vec::new();
+        for entry in main.iter(&wtxn)? {
+            let (key, value) = entry?;
+            let valid = match key {
+                b"version" => is_valid::<u64>(value),
+                b"login" => is_valid::<User>(value),
+                b"pending" => {
+                    rebuild_pending = true;
+                    continue;
+                }
+                b"sync_lock" => is_valid::<Lease>(value),
+                b"sync_requested" => is_valid::<bool>(value),
+                b"conflict_policy" => is_valid::<Policy>(value),
+                b"score_format" => is_valid::<ScoreFormat>(value),
+                // named databases live in the main one
+                key if TABLES.contains(&key) => continue,
+                _ => {
+                    issues.push(Issue {
+                        record: format!("main/{}", Str::show(key)),
+                        problem: "unknown key".to_string(),
+                        fixed: false,
+                    });
+                    continue;
+                }
+            };
+            if !valid {
+                quarantine.push((key.to_vec(), value.to_vec()));
+            }
+        }
+        for (key, value) in quarantine {
+            let record = format!("main/{}", Str::show(&key));
+            self.quarantine(&mut wtxn, record, &value, repair, &mut issues)?;
+            if repair {
+                main.delete(&mut wtxn, &key)?;
+            }
+        }
+
+        self.check_table(&mut wtxn, "data", self.data, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "retry", self.retry, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "queued", self.queued, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "titles", self.titles, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "overrides", self.overrides, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "history", self.history, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "list", self.list, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "conflicts", self.conflicts, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "changes", self.changes, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "list_only", self.list_only, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "trackers", self.trackers, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "outbox", self.outbox, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "mappings", self.mappings, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "groups", self.groups, repair, &mut issues)?;
+        self.check_table(&mut wtxn, "manga", self.manga, repair, &mut issues)?;
+        self.check_table(
+            &mut wtxn,
+            "manga_pending",
+            self.manga_pending,
+            repair,
+            &mut issues,
+        )?;
+        self.check_table(&mut wtxn, "rewatches", self.rewatches, repair, &mut issues)?;
+        self.check_table(
+            &mut wtxn,
+            "rewatch_candidates",
+            self.rewatch_candidates,
+            repair,
+            &mut issues,
+        )?;
+        self.check_table(&mut wtxn, "media", self.media, repair, &mut issues)?;
+
+        // without repair, undecodable records are still there
+        let mut ids = Vec::new();
+        for entry in self.data.remap_types::<Bytes, Bytes>().iter(&wtxn)? {
+            let (key, value) = entry?;
+            if U64::is_valid(key) && U64::is_valid(value) {
+                ids.push(u64::from_le_bytes(key.try_into().unwrap()));
+            }
+        }
+
+        if rebuild_pending {
+            let pending = match self.pending(&wtxn) {
+                Ok(pending) => {
+                    let mut fixed = pending.clone();
+                    fixed.sort_unstable();
+                    fixed.dedup();
+                    fixed.retain(|id| ids.binary_search(id).is_ok());
+                    if fixed != pending {
+                        issues.push(Issue {
+                            record: "main/pending".to_string(),
+                            problem: "unsorted or unknown anime in queue".to_string(),
+                            fixed: repair,
+                        });
+                    }
+                    fixed
+                }
+                Err(_) => {
+                    // we cannot tell what was already synced: queue everything,
+                    // anime already up to date are dropped by the next sync
+                    let value = self
+                        .main
+                        .get(&wtxn, "pending")?
+                        .map(<[u8]>::to_vec)
+                        .unwrap_or_default();
+                    if repair {
+                        self.corrupt
+                            .put(&mut wtxn, &format!("main/pending@{}", now()), &value)?;
+                    }
+                    issues.push(Issue {
+                        record: "main/pending".to_string(),
+                        problem: "cannot decode record, rebuilt from data".to_string(),
+                        fixed: repair,
+                    });
+                    ids.clone()
+                }
+            };
+            if repair {
+                self.main
+                    .put(&mut wtxn, "pending", &bincode_serialize(&pending)?)?;
+            }
+        }
+
+        if repair {
+            wtxn.commit()?;
+        }
+        Ok(issues)
+    }
+
+    /// Reports the records of `table` that its codecs cannot decode,
+    /// quarantining them with `repair`.
+    fn check_table<K: Codec, V: Codec>(
+        &self,
+        wtxn: &mut heed::RwTxn,
+        name: &str,
+        table: heed::Database<K, V>,
+        repair: bool,
+        issues: &mut Vec<Issue>,
+    ) -> heed::Result<()> {
+        let table = table.remap_types::<Bytes, Bytes>();
+        let mut quarantine = Vec::new();
+        for entry in table.iter(wtxn)? {
+            let (key, value) = entry?;
+            if !K::is_valid(key) || !V::is_valid(value) {
+                quarantine.push((key.to_vec(), value.to_vec()));
+            }
+        }
+        for (key, value) in quarantine {
+            let record = format!("{name}/{}", K::show(&key));
+            self.quarantine(wtxn, record, &value, repair, issues)?;
+            if repair {
+                table.delete(wtxn, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports `record` as undecodable, copying its `value` to the `corrupt`
+    /// table with `repair`. Deleting it is left to the caller.
+    fn quarantine(
+        &self,
+        wtxn: &mut heed::RwTxn,
+        record: String,
+        value: &[u8],
+        repair: bool,
+        issues: &mut Vec<Issue>,
+    ) -> heed::Result<()> {
+        if repair {
+            self.corrupt
+                .put(wtxn, &format!("{record}@{}", now()), value)?;
+        }
+        issues.push(Issue {
+            record,
+            problem: "cannot decode record, quarantined".to_string(),
+            fixed: repair,
+        });
+        Ok(())
+    }
+
+    /// Number of records quarantined so far.
+    pub fn corrupt_len(&self) -> heed::Result<u64> {
+        let rtxn = self.env.read_txn()?;
+        self.corrupt.len(&rtxn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heed::types::Bytes;
+
+    use crate::database::{VERSION, read_version, testing::TempDatabase};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn repair_quarantines_bad_version() {
+        let db = TempDatabase::new();
+        let mut wtxn = db.env.write_txn().unwrap();
+        db.main.put(&mut wtxn, "version", b"bad").unwrap();
+        assert!(read_version(db.main, &mut wtxn).is_err());
+        wtxn.commit().unwrap();
+
+        let issues = db.check(true).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].record, "main/version");
+        assert!(issues[0].fixed);
+        assert_eq!(db.corrupt_len().unwrap(), 1);
+
+        // migrated from the oldest on the next open
+        let mut wtxn = db.env.write_txn().unwrap();
+        assert_eq!(read_version(db.main, &mut wtxn).unwrap(), 0);
+        wtxn.commit().unwrap();
+        db.migrate(0).unwrap();
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(
+            db.main.get(&rtxn, "version").unwrap(),
+            Some(&VERSION.to_le_bytes()[..])
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn repair_quarantines_bad_records() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        let mut wtxn = db.env.write_txn().unwrap();
+        db.data
+            .remap_data_type::<Bytes>()
+            .put(&mut wtxn, &2, b"bad")
+            .unwrap();
+        db.changes
+            .remap_data_type::<Bytes>()
+            .put(&mut wtxn, &1, b"\xff")
+            .unwrap();
+        db.mappings
+            .remap_key_type::<Bytes>()
+            .put(&mut wtxn, b"mal/\xff", &1)
+            .unwrap();
+        wtxn.commit().unwrap();
+
+        let records = |issues: Vec<super::Issue>| {
+            issues
+                .into_iter()
+                .map(|issue| (issue.record, issue.fixed))
+                .collect::<Vec<_>>()
+        };
+        let expected = |fixed| {
+            vec![
+                ("data/2".to_string(), fixed),
+                ("changes/1".to_string(), fixed),
+                ("mappings/mal/\u{fffd}".to_string(), fixed),
+            ]
+        };
+        assert_eq!(records(db.check(false).unwrap()), expected(false));
+        assert_eq!(db.corrupt_len().unwrap(), 0);
+        assert_eq!(records(db.check(true).unwrap()), expected(true));
+        assert_eq!(db.corrupt_len().unwrap(), 3);
+        assert!(db.check(false).unwrap().is_empty());
+
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(db.data.get(&rtxn, &1).unwrap(), Some(5));
+        assert_eq!(db.data.get(&rtxn, &2).unwrap(), None);
+        assert_eq!(db.changes.get(&rtxn, &1).unwrap(), None);
+        assert_eq!(db.pending(&rtxn).unwrap(), [1]);
+    }
+}
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Database, bincode_deserialize, bincode_serialize};
+use crate::conflict::{Decision, Policy};
+
+/// A conflict met during sync and how it was settled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conflict {
+    pub id: u64,
+    /// see [`crate::tracker::Tracker::name`]
+    pub tracker: String,
+    pub local: u64,
+    pub remote: u64,
+    /// unix timestamp in seconds
+    pub remote_updated_at: Option<u64>,
+    pub policy: Policy,
+    pub decision: Decision,
+    /// unix timestamp in seconds
+    pub at: u64,
+}
+
+impl Database {
+    pub fn conflict_policy(&self) -> heed::Result<Policy> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self
+            .main
+            .get(&rtxn, "conflict_policy")?
+            .map(bincode_deserialize::<Policy>)
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    pub fn set_conflict_policy(&self, policy: Policy) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.main
+            .put(&mut wtxn, "conflict_policy", &bincode_serialize(&policy)?)?;
+        wtxn.commit()
+    }
+
+    /// Stores `conflict`, returning whether it was new: a deferred conflict
+    /// met again on every sync is recorded only once.
+    pub fn record_conflict(&self, conflict: &Conflict) -> heed::Result<bool> {
+        let mut wtxn = self.env.write_txn()?;
+        if conflict.decision == Decision::Deferred {
+            for entry in self.conflicts.rev_iter(&wtxn)? {
+                let (_, stored) = entry?;
+                if stored.id == conflict.id
+                    && stored.tracker == conflict.tracker
+                    && stored.local == conflict.local
+                    && stored.remote == conflict.remote
+                    && stored.decision == Decision::Deferred
+                {
+                    return Ok(false);
+                }
+            }
+        }
+        let seq = self.conflicts.last(&wtxn)?.map_or(0, |(seq, _)| seq + 1);
+        self.conflicts.put(&mut wtxn, &seq, conflict)?;
+        wtxn.commit()?;
+        Ok(true)
+    }
+
+    pub fn conflicts(&self) -> heed::Result<Vec<Conflict>> {
+        let rtxn = self.env.read_txn()?;
+        self.conflicts
+            .iter(&rtxn)?
+            .map(|entry| entry.map(|(_, conflict)| conflict))
+            .collect()
+    }
+
+    pub fn clear_conflicts(&self) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.conflicts.clear(&mut wtxn)?;
+        wtxn.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Conflict;
+    use crate::{
+        conflict::{Decision, Policy},
+        database::testing::TempDatabase,
+    };
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn deferred_conflicts_are_recorded_once() {
+        let db = TempDatabase::new();
+        let conflict = Conflict {
+            id: 1,
+            tracker: "anilist".to_string(),
+            local: 5,
+            remote: 7,
+            remote_updated_at: None,
+            policy: Policy::Ask,
+            decision: Decision::Deferred,
+            at: 0,
+        };
+        assert!(db.record_conflict(&conflict).unwrap());
+        assert!(!db.record_conflict(&conflict).unwrap());
+        assert!(
+            db.record_conflict(&Conflict {
+                remote: 8,
+                ..conflict.clone()
+            })
+            .unwrap()
+        );
+        let other = Conflict {
+            tracker: "mal".to_string(),
+            ..conflict.clone()
+        };
+        assert!(db.record_conflict(&other).unwrap());
+        let settled = Conflict {
+            decision: Decision::Local,
+            ..conflict
+        };
+        assert!(db.record_conflict(&settled).unwrap());
+        assert!(db.record_conflict(&settled).unwrap());
+        assert_eq!(db.conflicts().unwrap().len(), 5);
+    }
+}
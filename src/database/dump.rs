@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Database, User, history::HistoryEntry, manga::Reading};
+use crate::api::ListChange;
+
+/// Version of the dump format.
+pub const DUMP_VERSION: u64 = 1;
+
+/// Portable copy of the local database.
+///
+/// The queues of the other trackers are left out: they are rebuilt on import
+/// from the pending anime, for the trackers logged in there.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Dump {
+    pub version: u64,
+    pub login: Option<DumpLogin>,
+    pub pending: Vec<DumpPending>,
+    pub progress: Vec<DumpProgress>,
+    pub history: Vec<HistoryEntry>,
+    /// manga and light novels
+    #[serde(default)]
+    pub reading: Vec<DumpReading>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpLogin {
+    pub id: u64,
+    /// only exported on request
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpPending {
+    pub id: u64,
+    pub episode: u64,
+    /// unix timestamp in seconds
+    pub queued_at: Option<u64>,
+    pub force: bool,
+    /// list fields queued along with the episode
+    #[serde(default)]
+    pub change: Option<ListChange>,
+    /// only `change` is queued, the episode is not pushed
+    #[serde(default)]
+    pub list_only: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpProgress {
+    pub id: u64,
+    pub episode: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpReading {
+    pub id: u64,
+    pub chapter: u64,
+    pub volume: Option<u64>,
+    /// unix timestamp in seconds, set while waiting to be synced
+    pub queued_at: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub login: bool,
+    pub pending: usize,
+    pub progress: usize,
+    pub history: usize,
+    pub reading: usize,
+}
+
+impl Database {
+    pub fn export(&self, with_token: bool) -> heed::Result<Dump> {
+        let rtxn = self.env.read_txn()?;
+        let pending = self.pending(&rtxn)?;
+        let mut dump = Dump {
+            version: DUMP_VERSION,
+            login: self
+                .main
+                .get(&rtxn, "login")?
+                .map(super::bincode_deserialize::<User>)
+                .transpose()?
+                .map(|user| DumpLogin {
+                    id: user.id,
+                    token: with_token.then_some(user.token),
+                }),
+            ..Default::default()
+        };
+
+        for entry in self.data.iter(&rtxn)? {
+            let (id, episode) = entry?;
+            if pending.binary_search(&id).is_ok() {
+                dump.pending.push(DumpPending {
+                    id,
+                    episode,
+                    queued_at: self.queued.get(&rtxn, &id)?,
+                    force: self.overrides.get(&rtxn, &id)?.is_some(),
+                    change: self.changes.get(&rtxn, &id)?,
+                    list_only: self.list_only.get(&rtxn, &id)?.is_some(),
+                });
+            } else {
+                dump.progress.push(DumpProgress { id, episode });
+            }
+        }
+
+        for entry in self.history.iter(&rtxn)? {
+            dump.history.push(entry?.1);
+        }
+
+        for entry in self.manga.iter(&rtxn)? {
+            let (id, reading) = entry?;
+            dump.reading.push(DumpReading {
+                id,
+                chapter: reading.chapter,
+                volume: reading.volume,
+                queued_at: self
+                    .manga_pending
+                    .get(&rtxn, &id)?
+                    .map(|pending| pending.queued_at),
+            });
+        }
+        Ok(dump)
+    }
+
+    /// Merges `dump` into the database: episodes only move forward unless
+    /// forced, queued list changes are merged, and history already present is
+    /// skipped. Imported history is
+    /// kept for reference, but cannot be undone: its scrobbles belong to the
+    /// other machine.
+    pub fn import(&self, dump: &Dump) -> heed::Result<ImportReport> {
+        let mut report = ImportReport::default();
+        let mut wtxn = self.env.write_txn()?;
+
+        if let Some(DumpLogin {
+            id,
+            token: Some(token),
+        }) = &dump.login
+            && self.main.get(&wtxn, "login")?.is_none()
+        {
+            self.main.put(
+                &mut wtxn,
+                "login",
+                &super::bincode_serialize(&User {
+                    token: token.clone(),
+                    id: *id,
+                })?,
+            )?;
+            report.login = true;
+        }
+
+        for entry in &dump.progress {
+            if self
+                .data
+                .get(&wtxn, &entry.id)?
+                .is_none_or(|ep| ep < entry.episode)
+            {
+                self.data.put(&mut wtxn, &entry.id, &entry.episode)?;
+                report.progress += 1;
+            }
+        }
+
+        for entry in &dump.pending {
+            let ahead = !entry.list_only
+                && (entry.force
+                    || self
+                        .data
+                        .get(&wtxn, &entry.id)?
+                        .is_none_or(|ep| ep < entry.episode));
+            if !ahead && entry.change.is_none() {
+                continue;
+            }
+            if ahead {
+                self.data.put(&mut wtxn, &entry.id, &entry.episode)?;
+                if entry.force {
+                    self.overrides.put(&mut wtxn, &entry.id, &())?;
+                }
+                self.enqueue(&mut wtxn, entry.id)?;
+            }
+            if let Some(change) = &entry.change {
+                self.enqueue_change(&mut wtxn, entry.id, change)?;
+            }
+            if let Some(queued_at) = entry.queued_at
+                && self
+                    .queued
+                    .get(&wtxn, &entry.id)?
+                    .is_none_or(|at| at > queued_at)
+            {
+                self.queued.put(&mut wtxn, &entry.id, &queued_at)?;
+            }
+            report.pending += 1;
+        }
+
+        let mut seen = HashSet::new();
+        for entry in self.history.iter(&wtxn)? {
+            let (_, entry) = entry?;
+            seen.insert((entry.id, entry.episode, entry.at));
+        }
+        for entry in &dump.history {
+            if seen.insert((entry.id, entry.episode, entry.at)) {
+                let seq = self.history.last(&wtxn)?.map_or(0, |(seq, _)| seq + 1);
+                self.history.put(
+                    &mut wtxn,
+                    &seq,
+                    &HistoryEntry {
+                        imported: true,
+                        ..entry.clone()
+                    },
+                )?;
+                report.history += 1;
+            }
+        }
+
+        for entry in &dump.reading {
+            let reading = Reading {
+                chapter: entry.chapter,
+                volume: entry.volume,
+            };
+            let imported = match entry.queued_at {
+                Some(queued_at) => self.queue_reading(&mut wtxn, entry.id, reading, queued_at)?,
+                None if self
+                    .manga
+                    .get(&wtxn, &entry.id)?
+                    .is_none_or(|local| local.chapter < reading.chapter) =>
+                {
+                    self.manga.put(&mut wtxn, &entry.id, &reading)?;
+                    true
+                }
+                None => false,
+            };
+            if imported {
+                report.reading += 1;
+            }
+        }
+
+        wtxn.commit()?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{ListChange, MediaListStatus},
+        database::testing::TempDatabase,
+    };
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn import_keeps_list_changes_and_reading() {
+        let db = TempDatabase::new();
+        let change = ListChange {
+            status: Some(MediaListStatus::Paused),
+            ..Default::default()
+        };
+        db.queue_change(1, &change).unwrap();
+        db.scrobble(2, 5, false, Some(&change), &[]).unwrap();
+        db.read(3, 30, Some(3)).unwrap();
+        let dump = db.export(false).unwrap();
+
+        let other = TempDatabase::new();
+        let report = other.import(&dump).unwrap();
+        assert_eq!((report.pending, report.reading), (2, 1));
+        assert_eq!(other.pending_len().unwrap(), 2);
+        assert_eq!(other.manga_pending_len().unwrap(), 1);
+        let rtxn = other.env.read_txn().unwrap();
+        assert!(other.list_only.get(&rtxn, &1).unwrap().is_some());
+        assert!(other.list_only.get(&rtxn, &2).unwrap().is_none());
+        assert_eq!(other.data.get(&rtxn, &2).unwrap(), Some(5));
+        for id in [1, 2] {
+            assert_eq!(other.changes.get(&rtxn, &id).unwrap(), Some(change.clone()));
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Database, now};
+
+/// A scrobble as it was applied to the local database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub episode: u64,
+    /// local episode before the scrobble
+    pub previous: Option<u64>,
+    /// unix timestamp in seconds
+    pub at: u64,
+    pub undone: bool,
+    /// merged from another machine by [`Database::import`], so never undone
+    /// here
+    #[serde(default)]
+    pub imported: bool,
+}
+
+impl Database {
+    pub(super) fn record_history(
+        &self,
+        wtxn: &mut heed::RwTxn,
+        id: u64,
+        episode: u64,
+        previous: Option<u64>,
+    ) -> heed::Result<()> {
+        let seq = self.history.last(wtxn)?.map_or(0, |(seq, _)| seq + 1);
+        self.history.put(
+            wtxn,
+            &seq,
+            &HistoryEntry {
+                id,
+                episode,
+                previous,
+                at: now(),
+                undone: false,
+                imported: false,
+            },
+        )
+    }
+
+    /// Reverts the last `count` local scrobbles not undone yet, queueing the
+    /// previous episodes as overrides so that the decrease reaches AniList.
+    /// Each anime goes back to the episode before the oldest of its reverted
+    /// scrobbles.
+    pub fn undo(&self, count: usize) -> heed::Result<Vec<HistoryEntry>> {
+        let mut wtxn = self.env.write_txn()?;
+        let mut entries = Vec::new();
+        for entry in self.history.rev_iter(&wtxn)? {
+            if entries.len() == count {
+                break;
+            }
+            let (seq, entry) = entry?;
+            if !entry.undone && !entry.imported {
+                entries.push((seq, entry));
+            }
+        }
+
+        // newest first, so the oldest scrobble of each anime is the last one
+        let mut oldest: Vec<&HistoryEntry> = Vec::new();
+        for (_, entry) in &entries {
+            match oldest.iter_mut().find(|oldest| oldest.id == entry.id) {
+                Some(oldest) => *oldest = entry,
+                None => oldest.push(entry),
+            }
+        }
+        for entry in oldest {
+            // queued since before its first scrobble
+            let never_synced = self
+                .queued
+                .get(&wtxn, &entry.id)?
+                .is_some_and(|queued_at| queued_at <= entry.at)
+                && self.overrides.get(&wtxn, &entry.id)?.is_none();
+            match entry.previous {
+                // just forget it
+                None if never_synced => {
+                    self.dequeue(&mut wtxn, entry.id)?;
+                    self.undispatch(&mut wtxn, entry.id)?;
+                    self.data.delete(&mut wtxn, &entry.id)?;
+                }
+                previous => {
+                    self.data
+                        .put(&mut wtxn, &entry.id, &previous.unwrap_or(0))?;
+                    self.overrides.put(&mut wtxn, &entry.id, &())?;
+                    self.enqueue(&mut wtxn, entry.id)?;
+                }
+            }
+        }
+        for (seq, entry) in &mut entries {
+            entry.undone = true;
+            self.history.put(&mut wtxn, seq, entry)?;
+        }
+
+        wtxn.commit()?;
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HistoryEntry;
+    use crate::database::{dump::Dump, now, testing::TempDatabase};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn undo_unsynced_scrobbles_of_one_anime() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.scrobble(1, 6, false, None, &[]).unwrap();
+
+        let undone = db.undo(2).unwrap();
+        assert_eq!(undone.len(), 2);
+        assert!(undone.iter().all(|entry| entry.undone));
+        assert_eq!(db.pending_len().unwrap(), 0);
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(db.data.get(&rtxn, &1).unwrap(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn undo_back_to_oldest_previous() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.sync(false).unwrap().next().unwrap().update(5).unwrap();
+        db.scrobble(1, 6, false, None, &[]).unwrap();
+        db.scrobble(2, 1, false, None, &[]).unwrap();
+        db.scrobble(1, 7, false, None, &[]).unwrap();
+
+        db.undo(3).unwrap();
+
+        let anime: Vec<_> = db
+            .sync(false)
+            .unwrap()
+            .map(|anime| (anime.id(), anime.episode(), anime.is_override()))
+            .collect();
+        assert_eq!(anime, [(1, 5, true)]);
+
+        // the synced episode goes back to nothing on AniList too
+        db.undo(1).unwrap();
+        let anime = db.sync(false).unwrap().next().unwrap();
+        assert_eq!(
+            (anime.id(), anime.episode(), anime.is_override()),
+            (1, 0, true)
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn undo_skips_imported_scrobbles() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.import(&Dump {
+            history: vec![HistoryEntry {
+                id: 2,
+                episode: 3,
+                previous: Some(2),
+                at: now() + 60,
+                undone: false,
+                imported: false,
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let undone = db.undo(2).unwrap();
+        assert_eq!(undone.len(), 1);
+        assert_eq!(undone[0].id, 1);
+        assert!(db.undo(1).unwrap().is_empty());
+    }
+}
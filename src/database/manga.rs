@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Database, Retry, now};
+
+/// Reading progress of a manga or light novel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reading {
+    pub chapter: u64,
+    pub volume: Option<u64>,
+}
+
+/// Reading progress waiting to be synced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingReading {
+    pub reading: Reading,
+    /// unix timestamp in seconds
+    pub queued_at: u64,
+    pub retry: Option<Retry>,
+}
+
+impl Database {
+    /// Records reading `chapter` (and `volume`) of `id`, queueing it if
+    /// ahead of the local progress.
+    pub fn read(&self, id: u64, chapter: u64, volume: Option<u64>) -> heed::Result<bool> {
+        let mut wtxn = self.env.write_txn()?;
+        let queued = self.queue_reading(&mut wtxn, id, Reading { chapter, volume }, now())?;
+        wtxn.commit()?;
+        Ok(queued)
+    }
+
+    /// Merges `reading` into the local progress of `id`, queueing it as of
+    /// `queued_at` if ahead. Entries already queued keep the oldest time.
+    pub(super) fn queue_reading(
+        &self,
+        wtxn: &mut heed::RwTxn,
+        id: u64,
+        reading: Reading,
+        queued_at: u64,
+    ) -> heed::Result<bool> {
+        let previous = self.manga.get(wtxn, &id)?;
+        let reading = Reading {
+            chapter: previous.map_or(reading.chapter, |p| p.chapter.max(reading.chapter)),
+            volume: match (previous.and_then(|p| p.volume), reading.volume) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            },
+        };
+        if previous == Some(reading) {
+            return Ok(false);
+        }
+        self.manga.put(wtxn, &id, &reading)?;
+        let queued_at = self
+            .manga_pending
+            .get(wtxn, &id)?
+            .map_or(queued_at, |pending| pending.queued_at.min(queued_at));
+        self.manga_pending.put(
+            wtxn,
+            &id,
+            &PendingReading {
+                reading,
+                queued_at,
+                retry: None,
+            },
+        )?;
+        Ok(true)
+    }
+
+    pub fn manga_pending_len(&self) -> heed::Result<usize> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.manga_pending.len(&rtxn)? as usize)
+    }
+
+    /// Pending reading progress, skipping the entries waiting for a retry
+    /// unless `force` is set.
+    pub fn sync_manga(&self, force: bool) -> heed::Result<Vec<(u64, Reading)>> {
+        let rtxn = self.env.read_txn()?;
+        let mut pending = Vec::new();
+        for entry in self.manga_pending.iter(&rtxn)? {
+            let (id, entry) = entry?;
+            if force || entry.retry.is_none_or(|retry| retry.is_due()) {
+                pending.push((id, entry.reading));
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Marks `id` as synced at `remote`, unless read again since `reading`
+    /// was queued.
+    pub fn manga_synced(&self, id: u64, reading: Reading, remote: Reading) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        if self
+            .manga_pending
+            .get(&wtxn, &id)?
+            .is_some_and(|pending| pending.reading == reading)
+        {
+            self.manga_pending.delete(&mut wtxn, &id)?;
+            self.manga.put(&mut wtxn, &id, &remote)?;
+        }
+        wtxn.commit()
+    }
+
+    pub fn manga_failed(&self, id: u64) -> heed::Result<Retry> {
+        let mut wtxn = self.env.write_txn()?;
+        let mut pending = self.manga_pending.get(&wtxn, &id)?;
+        let retry = Retry::failed(pending.as_ref().and_then(|pending| pending.retry));
+        if let Some(pending) = &mut pending {
+            pending.retry = Some(retry);
+            self.manga_pending.put(&mut wtxn, &id, pending)?;
+        }
+        wtxn.commit()?;
+        Ok(retry)
+    }
+
+    /// Earliest retry scheduled for reading progress.
+    pub(super) fn manga_next_retry(&self, rtxn: &heed::RoTxn) -> heed::Result<Option<u64>> {
+        let mut next = None;
+        for entry in self.manga_pending.iter(rtxn)? {
+            if let Some(retry) = entry?.1.retry {
+                next = Some(next.map_or(retry.next_attempt, |n: u64| n.min(retry.next_attempt)));
+            }
+        }
+        Ok(next)
+    }
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Database, now};
+use crate::api::{MediaInfo, MediaStatus};
+
+/// How long details are cached, by airing status. Anime still airing also
+/// expire as soon as their next episode airs.
+const FINISHED_TTL: u64 = 30 * 24 * 60 * 60;
+const AIRING_TTL: u64 = 6 * 60 * 60;
+const UNKNOWN_TTL: u64 = 24 * 60 * 60;
+
+/// Details of an anime as last fetched from AniList.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedMedia {
+    pub info: MediaInfo,
+    /// unix timestamp in seconds
+    pub fetched_at: u64,
+}
+
+impl CachedMedia {
+    pub fn is_fresh(&self, now: u64) -> bool {
+        let ttl = match self.info.status {
+            Some(MediaStatus::Finished | MediaStatus::Cancelled) => FINISHED_TTL,
+            Some(MediaStatus::Releasing | MediaStatus::NotYetReleased | MediaStatus::Hiatus) => {
+                AIRING_TTL
+            }
+            None => UNKNOWN_TTL,
+        };
+        self.fetched_at + ttl > now
+            && self
+                .info
+                .next_airing
+                .is_none_or(|next| next.airing_at > now)
+    }
+}
+
+impl Database {
+    pub fn media(&self, id: u64) -> heed::Result<Option<CachedMedia>> {
+        let rtxn = self.env.read_txn()?;
+        self.media.get(&rtxn, &id)
+    }
+
+    /// Caches the details of `id`, along with its title.
+    pub fn set_media(&self, id: u64, info: &MediaInfo) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        if let Some(title) = &info.title {
+            self.titles.put(&mut wtxn, &id, title)?;
+        }
+        self.media.put(
+            &mut wtxn,
+            &id,
+            &CachedMedia {
+                info: info.clone(),
+                fetched_at: now(),
+            },
+        )?;
+        wtxn.commit()
+    }
+
+    pub fn cached_media(&self) -> heed::Result<Vec<(u64, CachedMedia)>> {
+        let rtxn = self.env.read_txn()?;
+        self.media.iter(&rtxn)?.collect()
+    }
+
+    /// Empties the cache, returning how many anime were in it.
+    pub fn clear_media(&self) -> heed::Result<u64> {
+        let mut wtxn = self.env.write_txn()?;
+        let len = self.media.len(&wtxn)?;
+        self.media.clear(&mut wtxn)?;
+        wtxn.commit()?;
+        Ok(len)
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+
+use super::{Database, VERSION, bincode_serialize};
+use crate::log;
+
+/// Migration from each version to the next one.
+const MIGRATIONS: [fn(&Database, &mut heed::RwTxn) -> heed::Result<()>; VERSION as usize] =
+    [new_tables];
+
+/// Version 1 adds the tables next to `data`. They are created empty when the
+/// database is opened, and the pending queue and the episodes of version 0
+/// are kept as they are.
+fn new_tables(_db: &Database, _wtxn: &mut heed::RwTxn) -> heed::Result<()> {
+    Ok(())
+}
+
+impl Database {
+    /// Brings the records stored by version `from` to the current layout.
+    pub(super) fn migrate(&self, from: u64) -> Result<()> {
+        let mut wtxn = self.env.write_txn().context("cannot migrate database")?;
+        for migration in &MIGRATIONS[from as usize..] {
+            migration(self, &mut wtxn).context("cannot migrate database")?;
+        }
+        self.main
+            .put(&mut wtxn, "version", &bincode_serialize(&VERSION)?)
+            .context("cannot migrate database")?;
+        wtxn.commit().context("cannot migrate database")?;
+        log::info!("migrated database from version {from} to {VERSION}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{VERSION, bincode_serialize, testing::TempDatabase};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn version_0_keeps_pending_episodes() {
+        let db = TempDatabase::new();
+        let mut wtxn = db.env.write_txn().unwrap();
+        db.main
+            .put(&mut wtxn, "version", &bincode_serialize(&0u64).unwrap())
+            .unwrap();
+        db.main
+            .put(
+                &mut wtxn,
+                "pending",
+                &bincode_serialize(&vec![1u64]).unwrap(),
+            )
+            .unwrap();
+        db.data.put(&mut wtxn, &1, &5).unwrap();
+        wtxn.commit().unwrap();
+
+        db.migrate(0).unwrap();
+
+        let anime = db.sync(false).unwrap().next().unwrap();
+        assert_eq!((anime.id(), anime.episode()), (1, 5));
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(
+            db.main.get(&rtxn, "version").unwrap(),
+            Some(&VERSION.to_le_bytes()[..])
+        );
+    }
+}
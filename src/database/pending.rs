@@ -0,0 +1,148 @@
+use serde::Serialize;
+
+use super::{Database, bincode_serialize, now};
+
+#[derive(Debug, Serialize)]
+pub struct PendingEntry {
+    pub id: u64,
+    pub title: Option<String>,
+    pub episode: u64,
+    /// pushed even if lower than the remote progress
+    pub force: bool,
+    /// unix timestamp in seconds
+    pub queued_at: Option<u64>,
+}
+
+impl Database {
+    /// Adds `id` to the pending queue, keeping the time it was first queued.
+    /// Its local episode is pushed, see [`Database::enqueue_change`] to only
+    /// push list fields.
+    pub(super) fn enqueue(&self, wtxn: &mut heed::RwTxn, id: u64) -> heed::Result<()> {
+        self.list_only.delete(wtxn, &id)?;
+        self.push_pending(wtxn, id)
+    }
+
+    pub(super) fn push_pending(&self, wtxn: &mut heed::RwTxn, id: u64) -> heed::Result<()> {
+        let mut pending = self.pending(wtxn)?;
+        if let Err(i) = pending.binary_search(&id) {
+            pending.insert(i, id);
+            self.main
+                .put(wtxn, "pending", &bincode_serialize(&pending)?)?;
+        }
+        if self.queued.get(wtxn, &id)?.is_none() {
+            self.queued.put(wtxn, &id, &now())?;
+        }
+        self.retry.delete(wtxn, &id)?;
+        self.dispatch(wtxn, id)
+    }
+
+    /// Removes `id` from the pending queue, returning whether it was there.
+    pub(super) fn dequeue(&self, wtxn: &mut heed::RwTxn, id: u64) -> heed::Result<bool> {
+        let mut pending = self.pending(wtxn)?;
+        let found = if let Ok(i) = pending.binary_search(&id) {
+            pending.remove(i);
+            self.main
+                .put(wtxn, "pending", &bincode_serialize(&pending)?)?;
+            true
+        } else {
+            false
+        };
+        self.queued.delete(wtxn, &id)?;
+        self.retry.delete(wtxn, &id)?;
+        self.overrides.delete(wtxn, &id)?;
+        self.changes.delete(wtxn, &id)?;
+        self.list_only.delete(wtxn, &id)?;
+        self.rewatch_candidates.delete(wtxn, &id)?;
+        Ok(found)
+    }
+
+    pub fn pending_entries(&self) -> heed::Result<Vec<PendingEntry>> {
+        let rtxn = self.env.read_txn()?;
+        let mut entries = Vec::new();
+        for id in self.pending(&rtxn)? {
+            let Some(episode) = self.data.get(&rtxn, &id)? else {
+                continue;
+            };
+            entries.push(PendingEntry {
+                id,
+                title: self.titles.get(&rtxn, &id)?.map(str::to_string),
+                episode,
+                force: self.overrides.get(&rtxn, &id)?.is_some(),
+                queued_at: self.queued.get(&rtxn, &id)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Drops the unsynced progress of `id`, returning whether it was queued.
+    ///
+    /// The last scrobbled episode is kept, so that replaying older episodes
+    /// does not queue them again.
+    pub fn remove_pending(&self, id: u64) -> heed::Result<bool> {
+        let mut wtxn = self.env.write_txn()?;
+        let found = self.dequeue(&mut wtxn, id)?;
+        self.undispatch(&mut wtxn, id)?;
+        wtxn.commit()?;
+        Ok(found)
+    }
+
+    /// Queues `episode` for `id`, even if lower than the stored one.
+    pub fn set_pending(&self, id: u64, episode: u64) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.data.put(&mut wtxn, &id, &episode)?;
+        self.enqueue(&mut wtxn, id)?;
+        wtxn.commit()
+    }
+
+    /// Drops every unsynced progress, returning how many were queued. Like
+    /// [`Database::remove_pending`], the last scrobbled episodes are kept.
+    pub fn clear_pending(&self) -> heed::Result<usize> {
+        let mut wtxn = self.env.write_txn()?;
+        let pending = self.pending(&wtxn)?;
+        for id in &pending {
+            self.queued.delete(&mut wtxn, id)?;
+            self.retry.delete(&mut wtxn, id)?;
+            self.overrides.delete(&mut wtxn, id)?;
+            self.changes.delete(&mut wtxn, id)?;
+            self.list_only.delete(&mut wtxn, id)?;
+            self.rewatch_candidates.delete(&mut wtxn, id)?;
+            self.undispatch(&mut wtxn, *id)?;
+        }
+        self.main.delete(&mut wtxn, "pending")?;
+        wtxn.commit()?;
+        Ok(pending.len())
+    }
+
+    pub fn set_title(&self, id: u64, title: &str) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.titles.put(&mut wtxn, &id, title)?;
+        wtxn.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::MediaListStatus, database::testing::TempDatabase};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn remove_pending_keeps_last_episode() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.scrobble(2, 3, false, None, &[]).unwrap();
+
+        assert!(db.remove_pending(1).unwrap());
+        assert_eq!(db.pending_len().unwrap(), 1);
+        db.set_list_status(1, MediaListStatus::Current).unwrap();
+        db.scrobble(1, 4, false, None, &[]).unwrap();
+        assert_eq!(db.pending_len().unwrap(), 1);
+
+        assert_eq!(db.clear_pending().unwrap(), 1);
+        db.scrobble(2, 3, false, None, &[]).unwrap();
+        assert_eq!(db.pending_len().unwrap(), 0);
+
+        let rtxn = db.env.read_txn().unwrap();
+        assert_eq!(db.data.get(&rtxn, &1).unwrap(), Some(5));
+        assert_eq!(db.data.get(&rtxn, &2).unwrap(), Some(3));
+    }
+}
@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+
+use super::Database;
+use crate::{
+    api::{MediaList, MediaListStatus},
+    conflict,
+};
+
+/// Status and score of an anime in the viewer's list, as last seen remotely.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListState {
+    pub status: Option<MediaListStatus>,
+    /// on a 0-10 scale
+    pub score: Option<f64>,
+    /// unix timestamp in seconds
+    pub updated_at: Option<u64>,
+}
+
+/// A pending local episode that disagrees with the remote progress, see
+/// [`conflict::is_conflict`].
+#[derive(Debug, Clone, Serialize)]
+pub struct PullConflict {
+    pub id: u64,
+    pub local: u64,
+    pub remote: u64,
+    pub remote_updated_at: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct PullReport {
+    pub updated: usize,
+    pub conflicts: Vec<PullConflict>,
+}
+
+impl Database {
+    /// Stores the remote list locally. Pending anime keep their local
+    /// episode, and are reported when it conflicts with the remote one like
+    /// on sync: forced episodes and list changes alone never do.
+    pub fn pull(&self, entries: &[MediaList]) -> heed::Result<PullReport> {
+        let mut report = PullReport::default();
+        let mut wtxn = self.env.write_txn()?;
+        let pending = self.pending(&wtxn)?;
+
+        for entry in entries {
+            let id = entry.media_id;
+            let remote = entry.progress.unwrap_or(0);
+            if let Some(title) = entry
+                .media
+                .as_ref()
+                .and_then(|media| media.title.userPreferred.as_deref())
+            {
+                self.titles.put(&mut wtxn, &id, title)?;
+            }
+            self.list.put(
+                &mut wtxn,
+                &id,
+                &ListState {
+                    status: entry.status,
+                    score: entry.score,
+                    updated_at: entry.updated_at,
+                },
+            )?;
+
+            let local = self.data.get(&wtxn, &id)?;
+            if pending.binary_search(&id).is_ok() {
+                if let Some(local) = local
+                    && self.overrides.get(&wtxn, &id)?.is_none()
+                    && self.list_only.get(&wtxn, &id)?.is_none()
+                    && conflict::is_conflict(
+                        local,
+                        remote,
+                        entry.updated_at,
+                        self.queued.get(&wtxn, &id)?,
+                    )
+                {
+                    report.conflicts.push(PullConflict {
+                        id,
+                        local,
+                        remote,
+                        remote_updated_at: entry.updated_at,
+                    });
+                }
+                continue;
+            }
+            if local != Some(remote) {
+                self.data.put(&mut wtxn, &id, &remote)?;
+                report.updated += 1;
+            }
+        }
+
+        wtxn.commit()?;
+        Ok(report)
+    }
+
+    /// Records the status of `id` on AniList, as seen or saved by a sync.
+    pub fn set_list_status(&self, id: u64, status: MediaListStatus) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        let state = match self.list.get(&wtxn, &id)? {
+            Some(state) => ListState {
+                status: Some(status),
+                ..state
+            },
+            None => ListState {
+                status: Some(status),
+                score: None,
+                updated_at: None,
+            },
+        };
+        self.list.put(&mut wtxn, &id, &state)?;
+        wtxn.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::MediaList,
+        database::{now, testing::TempDatabase},
+    };
+
+    fn entry(id: u64, progress: u64, updated_at: u64) -> MediaList {
+        MediaList {
+            media_id: id,
+            status: None,
+            score: None,
+            progress: Some(progress),
+            repeat: None,
+            started_at: None,
+            completed_at: None,
+            updated_at: Some(updated_at),
+            notes: None,
+            media: None,
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn pull_reports_conflicts_like_sync() {
+        let db = TempDatabase::new();
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.scrobble(2, 5, false, None, &[]).unwrap();
+        db.scrobble(3, 5, false, None, &[]).unwrap();
+        db.scrobble(4, 5, true, None, &[]).unwrap();
+        let old = now() - 3600;
+        let report = db
+            .pull(&[
+                // behind and not touched since: the push settles it
+                entry(1, 3, old),
+                // ahead
+                entry(2, 7, old),
+                // behind, but changed after the scrobble
+                entry(3, 3, now() + 60),
+                // forced
+                entry(4, 7, old),
+                // not pending
+                entry(5, 7, old),
+            ])
+            .unwrap();
+        assert_eq!(
+            report
+                .conflicts
+                .iter()
+                .map(|conflict| (conflict.id, conflict.local, conflict.remote))
+                .collect::<Vec<_>>(),
+            [(2, 5, 7), (3, 5, 3)]
+        );
+        assert_eq!(report.updated, 1);
+        assert_eq!(db.pending_len().unwrap(), 4);
+    }
+}
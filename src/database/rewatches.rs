@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Database, now};
+use crate::api::MediaListStatus;
+
+/// A rewatch of an anime that was already completed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Rewatch {
+    /// rewatches started locally, this one included
+    pub cycle: u64,
+    /// unix timestamp in seconds
+    pub started_at: u64,
+    pub completed: bool,
+}
+
+impl Database {
+    pub fn rewatch(&self, id: u64) -> heed::Result<Option<Rewatch>> {
+        let rtxn = self.env.read_txn()?;
+        self.rewatches.get(&rtxn, &id)
+    }
+
+    /// Whether `id` was completed, as last seen remotely or at the end of
+    /// its last rewatch. `None` if its list status is unknown.
+    pub(super) fn is_completed(&self, rtxn: &heed::RoTxn, id: u64) -> heed::Result<Option<bool>> {
+        if let Some(rewatch) = self.rewatches.get(rtxn, &id)? {
+            return Ok(Some(rewatch.completed));
+        }
+        Ok(self
+            .list
+            .get(rtxn, &id)?
+            .and_then(|state| state.status)
+            .map(|status| status == MediaListStatus::Completed))
+    }
+
+    /// Starts a new rewatch cycle of `id`.
+    pub(super) fn start_rewatch(&self, wtxn: &mut heed::RwTxn, id: u64) -> heed::Result<()> {
+        let cycle = self.rewatches.get(wtxn, &id)?.map_or(0, |r| r.cycle) + 1;
+        self.rewatches.put(
+            wtxn,
+            &id,
+            &Rewatch {
+                cycle,
+                started_at: now(),
+                completed: false,
+            },
+        )
+    }
+
+    /// Marks the current rewatch of `id` as completed.
+    pub fn complete_rewatch(&self, id: u64) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        if let Some(mut rewatch) = self.rewatches.get(&wtxn, &id)?
+            && !rewatch.completed
+        {
+            rewatch.completed = true;
+            self.rewatches.put(&mut wtxn, &id, &rewatch)?;
+        }
+        wtxn.commit()
+    }
+}
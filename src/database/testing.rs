@@ -0,0 +1,40 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::Database;
+
+/// A database in a fresh temporary directory, removed on drop.
+pub struct TempDatabase {
+    db: Database,
+    path: PathBuf,
+}
+
+impl TempDatabase {
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "aniscrobble-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        _ = std::fs::remove_dir_all(&path);
+        let db = Database::open(&path, true).unwrap();
+        Self { db, path }
+    }
+}
+
+impl std::ops::Deref for TempDatabase {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.path);
+    }
+}
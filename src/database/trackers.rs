@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Anime, Database, Retry, SyncContext, now};
+use crate::{api::ListChange, tracker::ACCOUNT_PREFIX};
+
+/// Login to a tracker other than AniList, whose login is kept in `main`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub user_id: u64,
+    pub token: String,
+    pub refresh_token: Option<String>,
+    /// unix timestamp in seconds
+    pub expires_at: Option<u64>,
+    /// OAuth client the tokens were issued to
+    pub client_id: Option<String>,
+}
+
+/// A change waiting to be synced to a tracker other than AniList.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outgoing {
+    pub episode: u64,
+    /// pushed even if lower than the remote progress
+    pub force: bool,
+    /// see [`Anime::is_list_only`]
+    pub list_only: bool,
+    pub change: Option<ListChange>,
+    /// unix timestamp in seconds
+    pub queued_at: u64,
+    pub retry: Option<Retry>,
+}
+
+fn outbox_key(tracker: &str, id: u64) -> String {
+    format!("{tracker}/{id}")
+}
+
+impl Database {
+    pub fn trackers(&self) -> heed::Result<Vec<(String, Credentials)>> {
+        let rtxn = self.env.read_txn()?;
+        self.trackers
+            .iter(&rtxn)?
+            .map(|entry| entry.map(|(name, credentials)| (name.to_string(), credentials)))
+            .collect()
+    }
+
+    pub fn tracker(&self, name: &str) -> heed::Result<Option<Credentials>> {
+        let rtxn = self.env.read_txn()?;
+        self.trackers.get(&rtxn, name)
+    }
+
+    /// Adds a tracker, or replaces its credentials. Anime already pending
+    /// are not sent to a new tracker.
+    pub fn set_tracker(&self, name: &str, credentials: &Credentials) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.trackers.put(&mut wtxn, name, credentials)?;
+        wtxn.commit()
+    }
+
+    /// Id of the AniList anime `id` on `tracker`, as cached by
+    /// [`Database::set_mapping`].
+    pub fn mapping(&self, tracker: &str, id: u64) -> heed::Result<Option<u64>> {
+        let rtxn = self.env.read_txn()?;
+        self.mappings.get(&rtxn, &outbox_key(tracker, id))
+    }
+
+    pub fn set_mapping(&self, tracker: &str, id: u64, remote_id: u64) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.mappings
+            .put(&mut wtxn, &outbox_key(tracker, id), &remote_id)?;
+        wtxn.commit()
+    }
+
+    pub fn groups(&self) -> heed::Result<Vec<(String, Vec<String>)>> {
+        let rtxn = self.env.read_txn()?;
+        self.groups
+            .iter(&rtxn)?
+            .map(|entry| entry.map(|(name, accounts)| (name.to_string(), accounts)))
+            .collect()
+    }
+
+    /// Trackers of the accounts in group `name`.
+    pub fn group(&self, name: &str) -> heed::Result<Option<Vec<String>>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.groups.get(&rtxn, name)?.map(|accounts| {
+            accounts
+                .into_iter()
+                .map(|account| format!("{ACCOUNT_PREFIX}{account}"))
+                .collect()
+        }))
+    }
+
+    pub fn add_to_group(&self, name: &str, accounts: &[String]) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        let mut members = self.groups.get(&wtxn, name)?.unwrap_or_default();
+        for account in accounts {
+            if !members.contains(account) {
+                members.push(account.clone());
+            }
+        }
+        self.groups.put(&mut wtxn, name, &members)?;
+        wtxn.commit()
+    }
+
+    /// Removes `accounts` from group `name`, or the whole group if empty.
+    /// Returns whether the group was there.
+    pub fn remove_from_group(&self, name: &str, accounts: &[String]) -> heed::Result<bool> {
+        let mut wtxn = self.env.write_txn()?;
+        let Some(mut members) = self.groups.get(&wtxn, name)? else {
+            return Ok(false);
+        };
+        members.retain(|member| !accounts.is_empty() && !accounts.contains(member));
+        if members.is_empty() {
+            self.groups.delete(&mut wtxn, name)?;
+        } else {
+            self.groups.put(&mut wtxn, name, &members)?;
+        }
+        wtxn.commit()?;
+        Ok(true)
+    }
+
+    /// Removes a tracker with its queue, returning whether it was there.
+    pub fn delete_tracker(&self, name: &str) -> heed::Result<bool> {
+        let mut wtxn = self.env.write_txn()?;
+        let found = self.trackers.delete(&mut wtxn, name)?;
+        let mut iter = self
+            .outbox
+            .prefix_iter_mut(&mut wtxn, &format!("{name}/"))?;
+        while iter.next().transpose()?.is_some() {
+            // SAFETY: no reference into the database is kept
+            unsafe { iter.del_current()? };
+        }
+        drop(iter);
+        wtxn.commit()?;
+        Ok(found)
+    }
+
+    pub fn outbox_len(&self, tracker: &str) -> heed::Result<usize> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self
+            .outbox
+            .prefix_iter(&rtxn, &format!("{tracker}/"))?
+            .count())
+    }
+
+    /// Queues the local state of `id` for every tracker other than AniList,
+    /// except extra AniList accounts, which only get scrobbles sent to their
+    /// group.
+    pub(super) fn dispatch(&self, wtxn: &mut heed::RwTxn, id: u64) -> heed::Result<()> {
+        let names = self
+            .trackers
+            .iter(wtxn)?
+            .map(|entry| entry.map(|(name, _)| name.to_string()))
+            .filter(|name| {
+                name.as_ref()
+                    .map_or(true, |name| !name.starts_with(ACCOUNT_PREFIX))
+            })
+            .collect::<heed::Result<Vec<_>>>()?;
+        self.dispatch_to(wtxn, id, &names)
+    }
+
+    /// Queues the local state of `id` for the trackers in `names`, skipping
+    /// the ones not configured.
+    pub(super) fn dispatch_to(
+        &self,
+        wtxn: &mut heed::RwTxn,
+        id: u64,
+        names: &[String],
+    ) -> heed::Result<()> {
+        let Some(episode) = self.data.get(wtxn, &id)? else {
+            return Ok(());
+        };
+        let force = self.overrides.get(wtxn, &id)?.is_some();
+        let list_only = self.list_only.get(wtxn, &id)?.is_some();
+        let change = self.changes.get(wtxn, &id)?;
+        for name in names {
+            if self.trackers.get(wtxn, name)?.is_none() {
+                continue;
+            }
+            let key = outbox_key(name, id);
+            let queued_at = self
+                .outbox
+                .get(wtxn, &key)?
+                .map_or_else(now, |outgoing| outgoing.queued_at);
+            self.outbox.put(
+                wtxn,
+                &key,
+                &Outgoing {
+                    episode,
+                    force,
+                    list_only,
+                    change: change.clone(),
+                    queued_at,
+                    retry: None,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Drops `id` from the queue of every tracker other than AniList.
+    pub(super) fn undispatch(&self, wtxn: &mut heed::RwTxn, id: u64) -> heed::Result<()> {
+        let names = self
+            .trackers
+            .iter(wtxn)?
+            .map(|entry| entry.map(|(name, _)| name.to_string()))
+            .collect::<heed::Result<Vec<_>>>()?;
+        for name in names {
+            self.outbox.delete(wtxn, &outbox_key(&name, id))?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Database::sync`], for the queue of another tracker.
+    pub fn sync_tracker(&self, tracker: &str, force: bool) -> heed::Result<SyncContext<'_>> {
+        let rtxn = self.env.read_txn()?;
+        let mut pending = Vec::new();
+        for entry in self.outbox.prefix_iter(&rtxn, &format!("{tracker}/"))? {
+            let (key, outgoing) = entry?;
+            let Some(Ok(id)) = key.rsplit_once('/').map(|(_, id)| id.parse()) else {
+                continue;
+            };
+            if force || outgoing.retry.as_ref().is_none_or(Retry::is_due) {
+                pending.push(Anime {
+                    db: self,
+                    tracker: Some(tracker.to_string()),
+                    id,
+                    episode: outgoing.episode,
+                    is_override: outgoing.force,
+                    is_list_only: outgoing.list_only,
+                    queued_at: Some(outgoing.queued_at),
+                    change: outgoing.change,
+                    // told apart by the AniList sync
+                    rewatch_candidate: None,
+                });
+            }
+        }
+        Ok(SyncContext {
+            pending: pending.into_iter(),
+        })
+    }
+
+    /// Marks `id` as synced to `tracker`, unless it was queued again since
+    /// `episode` and `change` were read.
+    pub(super) fn outbox_synced(
+        &self,
+        tracker: &str,
+        id: u64,
+        episode: u64,
+        change: Option<&ListChange>,
+    ) -> heed::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        let key = outbox_key(tracker, id);
+        if self.outbox.get(&wtxn, &key)?.is_some_and(|outgoing| {
+            outgoing.episode == episode && outgoing.change.as_ref() == change
+        }) {
+            self.outbox.delete(&mut wtxn, &key)?;
+        }
+        wtxn.commit()
+    }
+
+    pub(super) fn outbox_failed(&self, tracker: &str, id: u64) -> heed::Result<Retry> {
+        let mut wtxn = self.env.write_txn()?;
+        let key = outbox_key(tracker, id);
+        let mut outgoing = self.outbox.get(&wtxn, &key)?;
+        let retry = Retry::failed(outgoing.as_ref().and_then(|outgoing| outgoing.retry));
+        if let Some(outgoing) = &mut outgoing {
+            outgoing.retry = Some(retry);
+            self.outbox.put(&mut wtxn, &key, outgoing)?;
+        }
+        wtxn.commit()?;
+        Ok(retry)
+    }
+
+    /// Earliest retry scheduled in the queue of any configured tracker. The
+    /// queues of trackers removed since are never synced, so they are skipped.
+    pub(super) fn outbox_next_retry(&self, rtxn: &heed::RoTxn) -> heed::Result<Option<u64>> {
+        let mut next = None;
+        for entry in self.outbox.iter(rtxn)? {
+            let (key, outgoing) = entry?;
+            let Some((tracker, _)) = key.rsplit_once('/') else {
+                continue;
+            };
+            if self.trackers.get(rtxn, tracker)?.is_none() {
+                continue;
+            }
+            if let Some(retry) = outgoing.retry {
+                next = Some(next.map_or(retry.next_attempt, |n: u64| n.min(retry.next_attempt)));
+            }
+        }
+        Ok(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Credentials;
+    use crate::{api::ListChange, database::testing::TempDatabase};
+
+    fn add_mal(db: &TempDatabase) {
+        db.set_tracker(
+            "mal",
+            &Credentials {
+                user_id: 1,
+                token: "token".to_string(),
+                refresh_token: None,
+                expires_at: None,
+                client_id: None,
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn outbox_keeps_change_queued_during_sync() {
+        let db = TempDatabase::new();
+        add_mal(&db);
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+
+        let anime = db.sync_tracker("mal", false).unwrap().next().unwrap();
+        db.queue_change(
+            1,
+            &ListChange {
+                score: Some(8.0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        anime.update(5).unwrap();
+        assert_eq!(db.outbox_len("mal").unwrap(), 1);
+
+        let anime = db.sync_tracker("mal", false).unwrap().next().unwrap();
+        assert_eq!(anime.change().and_then(|change| change.score), Some(8.0));
+        anime.update(5).unwrap();
+        assert_eq!(db.outbox_len("mal").unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn removed_trackers_schedule_no_retry() {
+        let db = TempDatabase::new();
+        add_mal(&db);
+        db.scrobble(1, 5, false, None, &[]).unwrap();
+        db.sync(false).unwrap().next().unwrap().update(5).unwrap();
+        let retry = db
+            .sync_tracker("mal", false)
+            .unwrap()
+            .next()
+            .unwrap()
+            .failed()
+            .unwrap();
+        assert_eq!(db.next_retry().unwrap(), Some(retry.next_attempt));
+
+        // e.g. left behind by a newer version
+        let mut wtxn = db.env.write_txn().unwrap();
+        db.trackers.delete(&mut wtxn, "mal").unwrap();
+        wtxn.commit().unwrap();
+        assert_eq!(db.outbox_len("mal").unwrap(), 1);
+        assert_eq!(db.next_retry().unwrap(), None);
+    }
+}
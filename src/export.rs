@@ -0,0 +1,377 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{Context, Result, bail};
+
+use crate::database::{
+    HistoryEntry,
+    dump::{DUMP_VERSION, Dump, DumpLogin, DumpPending, DumpProgress, DumpReading},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// Guesses the format from the file extension, defaulting to JSON.
+    pub fn from_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// All the records share the same columns, `record` telling which ones are
+/// meaningful. `reading` records keep the chapter in `episode`, and the list
+/// changes of `pending` ones are stored as JSON.
+const CSV_HEADER: &str = "record,id,episode,previous,at,force,undone,token,volume,list_only,change";
+
+pub fn write(dump: &Dump, format: Format, mut w: impl Write) -> Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut w, dump)?;
+            writeln!(w)?;
+        }
+        Format::Csv => write_csv(dump, &mut w)?,
+    }
+    Ok(())
+}
+
+pub fn read(format: Format, r: impl BufRead) -> Result<Dump> {
+    let dump: Dump = match format {
+        Format::Json => serde_json::from_reader(r).context("invalid JSON dump")?,
+        Format::Csv => read_csv(r)?,
+    };
+    if dump.version > DUMP_VERSION {
+        bail!("unsupported dump version {}", dump.version);
+    }
+    Ok(dump)
+}
+
+fn opt<T: ToString>(v: Option<T>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_csv(dump: &Dump, w: &mut impl Write) -> Result<()> {
+    writeln!(w, "{CSV_HEADER}")?;
+    if let Some(login) = &dump.login {
+        writeln!(
+            w,
+            "login,{},,,,,,{},,,",
+            login.id,
+            quote(login.token.as_deref().unwrap_or(""))
+        )?;
+    }
+    for entry in &dump.pending {
+        writeln!(
+            w,
+            "pending,{},{},,{},{},,,,{},{}",
+            entry.id,
+            entry.episode,
+            opt(entry.queued_at),
+            entry.force,
+            entry.list_only,
+            quote(&opt(entry
+                .change
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?))
+        )?;
+    }
+    for entry in &dump.progress {
+        writeln!(w, "progress,{},{},,,,,,,,", entry.id, entry.episode)?;
+    }
+    for entry in &dump.history {
+        writeln!(
+            w,
+            "history,{},{},{},{},,{},,,,",
+            entry.id,
+            entry.episode,
+            opt(entry.previous),
+            entry.at,
+            entry.undone
+        )?;
+    }
+    for entry in &dump.reading {
+        writeln!(
+            w,
+            "reading,{},{},,{},,,,{},,",
+            entry.id,
+            entry.chapter,
+            opt(entry.queued_at),
+            opt(entry.volume)
+        )?;
+    }
+    Ok(())
+}
+
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(core::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn read_csv(r: impl BufRead) -> Result<Dump> {
+    fn num(field: &str, line: usize) -> Result<u64> {
+        field
+            .parse()
+            .with_context(|| format!("invalid number {field:?} at line {line}"))
+    }
+
+    fn opt_num(field: &str, line: usize) -> Result<Option<u64>> {
+        if field.is_empty() {
+            Ok(None)
+        } else {
+            num(field, line).map(Some)
+        }
+    }
+
+    fn flag(field: &str, line: usize) -> Result<bool> {
+        match field {
+            "true" => Ok(true),
+            "false" | "" => Ok(false),
+            _ => bail!("invalid boolean {field:?} at line {line}"),
+        }
+    }
+
+    let mut dump = Dump {
+        version: DUMP_VERSION,
+        ..Default::default()
+    };
+    let mut line = String::new();
+    let mut n = 0;
+    for (i, next) in r.lines().enumerate() {
+        let next = next?;
+        if line.is_empty() {
+            n = i + 1;
+            line = next;
+        } else {
+            // a quoted field spanning lines
+            line.push('\n');
+            line.push_str(&next);
+        }
+        if line.matches('"').count() % 2 == 1 {
+            continue;
+        }
+        let line = core::mem::take(&mut line);
+        if i == 0 && line == CSV_HEADER || line.is_empty() {
+            continue;
+        }
+        let fields = split_csv(&line);
+        let [
+            record,
+            id,
+            episode,
+            previous,
+            at,
+            force,
+            undone,
+            token,
+            volume,
+            list_only,
+            change,
+        ] = &fields[..]
+        else {
+            bail!("expected 11 fields at line {n}");
+        };
+        let id = num(id, n)?;
+        match record.as_str() {
+            "login" => {
+                dump.login = Some(DumpLogin {
+                    id,
+                    token: (!token.is_empty()).then(|| token.clone()),
+                })
+            }
+            "pending" => dump.pending.push(DumpPending {
+                id,
+                episode: num(episode, n)?,
+                queued_at: opt_num(at, n)?,
+                force: flag(force, n)?,
+                change: if change.is_empty() {
+                    None
+                } else {
+                    Some(
+                        serde_json::from_str(change)
+                            .with_context(|| format!("invalid list change at line {n}"))?,
+                    )
+                },
+                list_only: flag(list_only, n)?,
+            }),
+            "progress" => dump.progress.push(DumpProgress {
+                id,
+                episode: num(episode, n)?,
+            }),
+            "history" => dump.history.push(HistoryEntry {
+                id,
+                episode: num(episode, n)?,
+                previous: opt_num(previous, n)?,
+                at: num(at, n)?,
+                undone: flag(undone, n)?,
+                imported: false,
+            }),
+            "reading" => dump.reading.push(DumpReading {
+                id,
+                chapter: num(episode, n)?,
+                volume: opt_num(volume, n)?,
+                queued_at: opt_num(at, n)?,
+            }),
+            _ => bail!("unknown record {record:?} at line {n}"),
+        }
+    }
+    if !line.is_empty() {
+        bail!("unterminated quote at line {n}");
+    }
+    Ok(dump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_csv, write_csv};
+    use crate::{
+        api::{ListChange, MediaListStatus},
+        database::{
+            HistoryEntry,
+            dump::{DUMP_VERSION, Dump, DumpLogin, DumpPending, DumpProgress, DumpReading},
+        },
+    };
+
+    fn dump(token: &str) -> Dump {
+        Dump {
+            version: DUMP_VERSION,
+            login: Some(DumpLogin {
+                id: 42,
+                token: Some(token.to_string()),
+            }),
+            pending: vec![
+                DumpPending {
+                    id: 1,
+                    episode: 3,
+                    queued_at: Some(1_700_000_000),
+                    force: true,
+                    change: None,
+                    list_only: false,
+                },
+                DumpPending {
+                    id: 2,
+                    episode: 1,
+                    queued_at: None,
+                    force: false,
+                    change: Some(ListChange {
+                        status: Some(MediaListStatus::Paused),
+                        notes: Some("on hold, for now".to_string()),
+                        ..Default::default()
+                    }),
+                    list_only: true,
+                },
+            ],
+            progress: vec![DumpProgress { id: 3, episode: 12 }],
+            history: vec![HistoryEntry {
+                id: 1,
+                episode: 3,
+                previous: Some(2),
+                at: 1_700_000_000,
+                undone: false,
+                imported: false,
+            }],
+            reading: vec![
+                DumpReading {
+                    id: 4,
+                    chapter: 30,
+                    volume: Some(3),
+                    queued_at: Some(1_700_000_000),
+                },
+                DumpReading {
+                    id: 5,
+                    chapter: 7,
+                    volume: None,
+                    queued_at: None,
+                },
+            ],
+        }
+    }
+
+    fn round_trip(dump: &Dump) -> (String, Dump) {
+        let mut csv = Vec::new();
+        write_csv(dump, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let read = read_csv(csv.as_bytes()).unwrap();
+        (csv, read)
+    }
+
+    fn assert_same(a: &Dump, b: &Dump) {
+        assert_eq!(
+            serde_json::to_value(a).unwrap(),
+            serde_json::to_value(b).unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trip_plain() {
+        let dump = dump("token");
+        let (csv, read) = round_trip(&dump);
+        assert_eq!(
+            csv,
+            "record,id,episode,previous,at,force,undone,token,volume,list_only,change\n\
+             login,42,,,,,,token,,,\n\
+             pending,1,3,,1700000000,true,,,,false,\n\
+             pending,2,1,,,false,,,,true,\"{\"\"status\"\":\"\"PAUSED\"\",\"\"score\"\":null,\
+             \"\"started_at\"\":null,\"\"completed_at\"\":null,\"\"repeat\"\":null,\
+             \"\"notes\"\":\"\"on hold, for now\"\",\"\"custom_lists\"\":null,\"\"private\"\":null,\
+             \"\"hidden\"\":null}\"\n\
+             progress,3,12,,,,,,,,\n\
+             history,1,3,2,1700000000,,false,,,,\n\
+             reading,4,30,,1700000000,,,,3,,\n\
+             reading,5,7,,,,,,,,\n"
+        );
+        assert_same(&dump, &read);
+    }
+
+    #[test]
+    fn round_trip_quoted() {
+        for token in ["a,b", "say \"hi\"", "\"", "line\nbreak", "a,\"b\"\n\nc,"] {
+            let dump = dump(token);
+            let (csv, read) = round_trip(&dump);
+            assert!(csv.contains(&format!("\"{}\"", token.replace('"', "\"\""))));
+            assert_same(&dump, &read);
+        }
+    }
+
+    #[test]
+    fn read_errors() {
+        for csv in [
+            "pending,1,3,,,true,,,,\n",
+            "pending,x,3,,,,,,,,\n",
+            "pending,1,3,,,yes,,,,,\n",
+            "pending,1,3,,,true,,,,,{\n",
+            "reading,1,,,,,,,,,\n",
+            "unknown,1,,,,,,,,,\n",
+            "login,1,,,,,,\"open,,,\n",
+        ] {
+            assert!(read_csv(csv.as_bytes()).is_err(), "{csv:?}");
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+/// Size after which the log file is rotated.
+const MAX_SIZE: u64 = 1024 * 1024;
+/// Number of rotated files kept around.
+const KEEP: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Logger {
+    level: Level,
+    /// `None` when logging to a file is disabled.
+    path: Option<PathBuf>,
+    lock: Mutex<()>,
+}
+
+fn logger() -> &'static Logger {
+    static LOGGER: OnceLock<Logger> = OnceLock::new();
+    LOGGER.get_or_init(|| Logger {
+        level: std::env::var("ANISCROBBLE_LOG")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(Level::Info),
+        // tests must not write to the log of the user
+        path: (!cfg!(test)).then(path),
+        lock: Mutex::new(()),
+    })
+}
+
+pub fn path() -> PathBuf {
+    crate::paths::log_path()
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    name.into()
+}
+
+impl Logger {
+    fn open(path: &Path) -> std::io::Result<File> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotate(path: &Path) -> std::io::Result<()> {
+        for n in (1..KEEP).rev() {
+            let from = rotated(path, n);
+            if from.exists() {
+                std::fs::rename(from, rotated(path, n + 1))?;
+            }
+        }
+        std::fs::rename(path, rotated(path, 1))
+    }
+
+    /// The file is reopened on every write, so that rotations done by other
+    /// processes are picked up.
+    fn write(&self, line: &str) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _lock = self.lock.lock().unwrap();
+        if std::fs::metadata(path).is_ok_and(|m| m.len() >= MAX_SIZE) {
+            Self::rotate(path)?;
+        }
+        Self::open(path)?.write_all(line.as_bytes())
+    }
+}
+
+pub fn log(level: Level, args: std::fmt::Arguments) {
+    let logger = logger();
+    if level > logger.level {
+        return;
+    }
+    let line = format!(
+        "{} {:5} [{}] {}\n",
+        timestamp(crate::database::now()),
+        level.as_str(),
+        std::process::id(),
+        args
+    );
+    _ = logger.write(&line);
+}
+
+/// Formats unix seconds as an UTC RFC 3339 timestamp.
+pub fn timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Parses an RFC 3339 timestamp, like `2024-05-01T12:00:00+09:00`, to
+/// unix seconds.
+pub fn parse_timestamp(s: &str) -> Option<u64> {
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(i) => time.split_at(i),
+        None => (time, "Z"),
+    };
+    let mut time = time
+        .split('.')
+        .next()?
+        .splitn(3, ':')
+        .map(str::parse::<i64>);
+    let (hour, min, sec) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    let offset = match offset.as_bytes().first() {
+        Some(b'+' | b'-') => {
+            let (h, m) = offset[1..].split_once(':')?;
+            let secs = h.parse::<i64>().ok()? * 3600 + m.parse::<i64>().ok()? * 60;
+            if offset.starts_with('-') { -secs } else { secs }
+        }
+        _ => 0,
+    };
+
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    u64::try_from(days * 86400 + hour * 3600 + min * 60 + sec - offset).ok()
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+pub(crate) use {debug, error, info, warning};
+
+/// Prints the last `lines` lines of the log, then keeps printing new ones if
+/// `follow` is set.
+pub fn tail(lines: usize, follow: bool) -> Result<()> {
+    let path = path();
+    let mut stdout = std::io::stdout().lock();
+
+    let mut file = match File::open(&path) {
+        Ok(file) => Some(file),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && follow => None,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("cannot open log file"),
+    };
+
+    let mut pos = 0;
+    if let Some(file) = file.as_mut() {
+        let mut last = std::collections::VecDeque::with_capacity(lines);
+        for line in BufReader::new(&mut *file).lines() {
+            if last.len() == lines {
+                last.pop_front();
+            }
+            if lines > 0 {
+                last.push_back(line?);
+            }
+        }
+        for line in last {
+            writeln!(stdout, "{line}")?;
+        }
+        pos = file.stream_position()?;
+    }
+
+    if !follow {
+        return Ok(());
+    }
+
+    let mut buf = Vec::new();
+    loop {
+        stdout.flush()?;
+        std::thread::sleep(Duration::from_millis(500));
+
+        let len = match std::fs::metadata(&path) {
+            Ok(m) => m.len(),
+            Err(_) => continue,
+        };
+        // rotated or truncated
+        if file.is_none() || len < pos {
+            file = Some(File::open(&path).context("cannot open log file")?);
+            pos = 0;
+        }
+        let file = file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(pos))?;
+        buf.clear();
+        file.read_to_end(&mut buf)?;
+        pos += buf.len() as u64;
+        stdout.write_all(&buf)?;
+    }
+}
@@ -1,14 +1,28 @@
-use std::io::Write;
+use std::{io::Write, path::PathBuf};
 
 use anyhow::{Context, Result, bail};
-use api::Api;
+use api::{Api, ListChange, MediaListStatus, ScoreFormat};
 use clap::{Parser, Subcommand};
-use database::{Database, User};
+use conflict::{Decision, Policy};
+use database::{
+    Conflict, Credentials, Database, QueuedChange, Reading, SyncContext, SyncLock, User,
+};
+use tracker::{Tracker, TrackerKind};
 
 mod api;
+mod conflict;
+#[cfg(not(windows))]
+mod control;
 #[cfg(not(windows))]
 mod daemon;
 mod database;
+mod export;
+mod log;
+mod mal;
+mod paths;
+#[cfg(not(windows))]
+mod server;
+mod tracker;
 
 pub trait IsFatal {
     fn is_fatal(&self) -> bool;
@@ -18,6 +32,9 @@ pub trait IsFatal {
 #[command(version, about, long_about)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// database to use instead of the default one
+    #[arg(long, global = true, env = "ANISCROBBLE_DB")]
+    db: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -28,6 +45,15 @@ enum Commands {
         /// force login even if already logged in
         #[arg(short, long)]
         force: bool,
+        /// tracker to log in to
+        #[arg(short, long, value_enum, default_value_t)]
+        tracker: TrackerKind,
+        /// MyAnimeList API client id
+        #[arg(long, env = "ANISCROBBLE_MAL_CLIENT_ID")]
+        client_id: Option<String>,
+        /// log in to an extra AniList account with this name, see `group`
+        #[arg(short, long, conflicts_with = "tracker")]
+        account: Option<String>,
     },
     Sync,
     Scrobble {
@@ -37,24 +63,391 @@ enum Commands {
         /// do not sync
         #[arg(short, long)]
         local_only: bool,
+        /// accept episodes lower than the current progress, past the last
+        /// one or not aired yet, and push them to AniList
+        #[arg(short, long)]
+        force: bool,
+        /// also scrobble to the AniList accounts of this group
+        #[arg(short, long)]
+        group: Option<String>,
+        /// score in the score format of the AniList profile
+        #[arg(long)]
+        score: Option<f64>,
+        #[command(flatten)]
+        entry: EntryArgs,
         anilist_id: u64,
         episode: u64,
     },
+    /// Queue a score and other list fields of an anime without scrobbling
+    Rate {
+        /// sync in background
+        #[arg(short, long)]
+        background: bool,
+        /// do not sync
+        #[arg(short, long)]
+        local_only: bool,
+        #[command(flatten)]
+        entry: EntryArgs,
+        anilist_id: u64,
+        /// in the score format of the AniList profile
+        score: f64,
+    },
+    /// Track the chapters and volumes read of a manga or light novel
+    Read {
+        /// sync in background
+        #[arg(short, long)]
+        background: bool,
+        /// do not sync
+        #[arg(short, long)]
+        local_only: bool,
+        /// volume the chapter belongs to
+        #[arg(long)]
+        volume: Option<u64>,
+        manga_id: u64,
+        chapter: u64,
+    },
+    /// Manage groups of extra AniList accounts scrobbled to together
+    Group {
+        #[command(subcommand)]
+        command: Option<GroupCommands>,
+    },
+    /// Show conflicts met during sync and set how they are settled
+    Conflicts {
+        #[command(subcommand)]
+        command: Option<ConflictsCommands>,
+    },
+    /// Fetch the remote list into the local database
+    Pull,
+    /// Write the local database to a file
+    ///
+    /// The queues of MyAnimeList, Kitsu and the other AniList accounts are
+    /// not written: `import` rebuilds them from the pending anime, for the
+    /// trackers logged in there.
+    #[command(args_conflicts_with_subcommands = true)]
+    Export {
+        #[command(subcommand)]
+        command: Option<ExportCommands>,
+        /// output format
+        #[arg(long, value_enum, default_value_t = export::Format::Json)]
+        format: export::Format,
+        /// file to write, standard output if missing
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// include the AniList token
+        #[arg(long)]
+        with_token: bool,
+    },
+    /// Merge a file written by `export` into the local database
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Import {
+        #[command(subcommand)]
+        command: Option<ImportCommands>,
+        /// input format, guessed from the extension if missing
+        #[arg(long, value_enum)]
+        format: Option<export::Format>,
+        #[arg(required = true)]
+        file: Option<PathBuf>,
+    },
+    /// Revert the last scrobbles made on this machine
+    Undo {
+        /// sync in background
+        #[arg(short, long)]
+        background: bool,
+        /// do not sync
+        #[arg(short, long)]
+        local_only: bool,
+        /// number of scrobbles to revert
+        #[arg(default_value_t = 1)]
+        count: usize,
+    },
+    /// Keep running and serve scrobbles over a local socket
+    #[cfg(not(windows))]
+    Daemon {
+        /// run in background
+        #[arg(short, long)]
+        background: bool,
+    },
+    /// Show the daemon status
+    #[cfg(not(windows))]
+    Status,
+    /// Inspect and edit the queue of scrobbles waiting to be synced
+    Pending {
+        #[command(subcommand)]
+        command: PendingCommands,
+    },
+    /// List the trackers the queue is synced to
+    Trackers {
+        #[command(subcommand)]
+        command: Option<TrackersCommands>,
+    },
+    /// Search anime by title
+    Search {
+        /// only anime aired in this year
+        #[arg(long)]
+        year: Option<u32>,
+        /// only anime aired in this season
+        #[arg(long, value_enum)]
+        season: Option<api::MediaSeason>,
+        #[arg(long, value_enum)]
+        format: Option<api::MediaFormat>,
+        /// search another tracker instead, with its own ids
+        #[arg(short, long, value_enum, conflicts_with_all = ["year", "season", "format", "json"])]
+        tracker: Option<TrackerKind>,
+        /// print as JSON
+        #[arg(long)]
+        json: bool,
+        query: String,
+    },
+    /// Show the details of an anime and its entry in the list
+    Info {
+        /// print as JSON
+        #[arg(long)]
+        json: bool,
+        anilist_id: u64,
+    },
+    /// Manage the cached anime details
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// Inspect the local database
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+    /// Show the background sync log
+    Logs {
+        /// number of lines to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+        /// keep printing new lines
+        #[arg(short, long)]
+        follow: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum PendingCommands {
+    /// List queued scrobbles
+    List {
+        /// print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Drop the queued scrobble of an anime
+    Remove { anilist_id: u64 },
+    /// Queue an episode for an anime, replacing the local one
+    Set { anilist_id: u64, episode: u64 },
+    /// Drop every queued scrobble
+    Clear,
+}
+
+#[derive(Debug, Subcommand)]
+enum ExportCommands {
+    /// Write the AniList anime list as a MyAnimeList XML export
+    Mal {
+        /// file to write, standard output if missing
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ImportCommands {
+    /// Queue the list of a MyAnimeList XML export for sync
+    Mal {
+        /// only report what would be queued
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        file: PathBuf,
+    },
+}
+
+/// List fields sent along with the progress.
+#[derive(Debug, clap::Args)]
+struct EntryArgs {
+    /// taken by the commands themselves, in the score format of the
+    /// AniList profile
+    #[arg(skip)]
+    score: Option<f64>,
+    /// private notes of the entry
+    #[arg(long)]
+    notes: Option<String>,
+    /// put the entry in this custom list, can be repeated
+    #[arg(long = "custom-list")]
+    custom_lists: Vec<String>,
+    /// make the entry private, or public with `--private=false`
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    private: Option<bool>,
+    /// hide the entry from the status lists, or show it with `--hidden=false`
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    hidden: Option<bool>,
+}
+
+#[derive(Debug, Subcommand)]
+enum GroupCommands {
+    /// List groups and their accounts
+    List,
+    /// Add accounts to a group, creating it if needed
+    Add {
+        group: String,
+        #[arg(required = true)]
+        accounts: Vec<String>,
+    },
+    /// Remove accounts from a group, or the whole group if none is given
+    Remove {
+        group: String,
+        accounts: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum TrackersCommands {
+    /// List trackers with the number of queued scrobbles
+    List,
+    /// Log out of a tracker, dropping its queue
+    Remove { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConflictsCommands {
+    /// List recorded conflicts
+    List {
+        /// print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show or set the conflict resolution policy
+    Policy { policy: Option<Policy> },
+    /// Forget recorded conflicts
+    Clear,
+}
+
+#[derive(Debug, Subcommand)]
+enum CacheCommands {
+    /// Fetch again the details that expired
+    Refresh {
+        /// also fetch the ones that did not expire
+        #[arg(short, long)]
+        all: bool,
+    },
+    /// Remove all the cached details
+    Clear,
+}
+
+#[derive(Debug, Subcommand)]
+enum DbCommands {
+    /// Report records that cannot be decoded
+    Check,
+    /// Quarantine undecodable records and rebuild the pending queue
+    Repair,
 }
 
 #[inline(always)]
 fn _main() -> Result<()> {
     let mut cli = Cli::parse();
+    if let Some(db) = cli.db.take() {
+        paths::set_db_override(db);
+    }
     loop {
         match match cli.command {
-            Commands::Login { force } => login(force),
+            Commands::Login {
+                force,
+                tracker,
+                client_id,
+                account,
+            } => login(force, tracker, client_id, account),
             Commands::Sync => sync(None),
             Commands::Scrobble {
                 background,
                 local_only,
+                force,
+                group,
+                score,
+                mut entry,
                 anilist_id,
                 episode,
-            } => scrobble(anilist_id, episode, background, local_only),
+            } => {
+                entry.score = score;
+                scrobble(
+                    anilist_id, episode, force, group, entry, background, local_only,
+                )
+            }
+            Commands::Rate {
+                background,
+                local_only,
+                mut entry,
+                anilist_id,
+                score,
+            } => {
+                entry.score = Some(score);
+                rate(anilist_id, entry, background, local_only)
+            }
+            Commands::Read {
+                background,
+                local_only,
+                volume,
+                manga_id,
+                chapter,
+            } => read(manga_id, chapter, volume, background, local_only),
+            Commands::Group { command } => group(command),
+            Commands::Conflicts { command } => conflicts(command),
+            Commands::Pull => pull(),
+            Commands::Export {
+                command: Some(ExportCommands::Mal { output }),
+                ..
+            } => export_mal(output),
+            Commands::Export {
+                command: None,
+                format,
+                output,
+                with_token,
+            } => export(format, output, with_token),
+            Commands::Import {
+                command: Some(ImportCommands::Mal { dry_run, file }),
+                ..
+            } => import_mal(file, dry_run),
+            Commands::Import {
+                command: None,
+                format,
+                file,
+            } => import(format, file.expect("required by clap")),
+            Commands::Undo {
+                background,
+                local_only,
+                count,
+            } => undo(count, background, local_only),
+            #[cfg(not(windows))]
+            Commands::Daemon { background } => daemon(background),
+            #[cfg(not(windows))]
+            Commands::Status => status(),
+            Commands::Pending { command } => pending(command),
+            Commands::Trackers { command } => trackers(command),
+            Commands::Search {
+                tracker: Some(tracker @ (TrackerKind::Mal | TrackerKind::Kitsu)),
+                query,
+                ..
+            } => search_tracker(tracker, query),
+            Commands::Search {
+                year,
+                season,
+                format,
+                json,
+                query,
+                ..
+            } => search(
+                query,
+                api::SearchFilter {
+                    year,
+                    season,
+                    format,
+                },
+                json,
+            ),
+            Commands::Info { json, anilist_id } => info(anilist_id, json),
+            Commands::Cache { command } => cache(command),
+            Commands::Db { command } => db(command),
+            Commands::Logs { lines, follow } => log::tail(lines, follow).map(|_| None),
         }? {
             Some(c) => cli = c,
             None => return Ok(()),
@@ -65,6 +458,7 @@ fn _main() -> Result<()> {
 #[cfg(not(debug_assertions))]
 fn main() {
     if let Err(err) = _main() {
+        log::error!("{err:#}");
         eprintln!("Error: {}", err);
         std::process::exit(1);
     }
@@ -72,81 +466,775 @@ fn main() {
 
 #[cfg(debug_assertions)]
 fn main() -> Result<()> {
-    _main()
+    _main().inspect_err(|err| log::error!("{err:#}"))
 }
 
 #[cfg(debug_assertions)]
 fn show_error<E: std::fmt::Debug + std::fmt::Display>(err: E) {
+    log::error!("{err:#}");
     eprintln!("Error: {err:#?}");
 }
 
 #[cfg(not(debug_assertions))]
 fn show_error<E: std::fmt::Debug + std::fmt::Display>(err: E) {
+    log::error!("{err:#}");
     eprintln!("Error: {err}");
 }
 
 fn sync(db: Option<Database>) -> Result<Option<Cli>> {
+    #[cfg(not(windows))]
+    if db.is_none()
+        && let Some(mut client) = control::Client::connect()?
+    {
+        client.request(&control::Request::Sync)?;
+        return Ok(None);
+    }
+
     let db = if let Some(db) = db {
         db
     } else {
         Database::new()?
     };
-    let Some(user) = db.login()? else {
+    for (name, outcome) in sync_database(&db, true)? {
+        println!("{name}: {outcome}");
+    }
+    Ok(None)
+}
+
+/// Pushes the pending queue to AniList and the other trackers. Anime that
+/// failed recently are retried only once their backoff expired, unless
+/// `force` is set.
+///
+/// Returns how the sync went for each tracker, empty if another process was
+/// already syncing.
+fn sync_database(db: &Database, force: bool) -> Result<Vec<(String, Outcome)>> {
+    let Some(user) = db
+        .login()
+        .context("cannot read login, try `aniscrobble db repair`")?
+    else {
         bail!("login not found")
     };
-    let api = Api::new();
-    let mut sync = db.sync()?;
-
-    while let Some(anime) = sync.next() {
-        let anime = anime?;
-        match api.get_progress(&user.token, user.id, anime.id()) {
-            Ok(api::Anime { progress, episodes }) => {
-                let episode = if anime.episode() > progress {
-                    if let Err(err) =
-                        api.set_progress(&user.token, anime.id(), anime.episode(), episodes)
-                    {
-                        show_error(err);
-                        None
+    let Some(mut lock) = db.lock_sync()? else {
+        log::info!("sync already running, asked it to run again");
+        return Ok(Vec::new());
+    };
+    let anilist = tracker::AniList::new(db.clone(), user.clone());
+    let mut outcomes = Vec::<(String, Outcome)>::new();
+    let mut add = |name: &str, outcome: Outcome| {
+        if outcome.synced + outcome.failed + outcome.deferred != 0 {
+            log::info!("{name}: {outcome}");
+        }
+        match outcomes.iter_mut().find(|(n, _)| n == name) {
+            Some((_, total)) => *total += outcome,
+            None => outcomes.push((name.to_string(), outcome)),
+        }
+    };
+    loop {
+        add(
+            "anilist",
+            sync_pending(db, &lock, &anilist, db.sync(force)?, force)?,
+        );
+        for (name, credentials) in db.trackers()? {
+            let Some(tracker) = tracker::open(db, &name, credentials) else {
+                log::warning!("unknown tracker {name}, skipping");
+                continue;
+            };
+            add(
+                &name,
+                sync_pending(db, &lock, &*tracker, db.sync_tracker(&name, force)?, force)?,
+            );
+        }
+        add("anilist manga", sync_manga(db, &lock, &user, force)?);
+        if !lock.finish()? {
+            return Ok(outcomes);
+        }
+        log::debug!("sync requested while running, running again");
+    }
+}
+
+/// Anime synced to a tracker, by result.
+#[derive(Debug, Default, Clone, Copy)]
+struct Outcome {
+    synced: usize,
+    failed: usize,
+    /// conflicts left for later
+    deferred: usize,
+}
+
+impl std::ops::AddAssign for Outcome {
+    fn add_assign(&mut self, rhs: Self) {
+        self.synced += rhs.synced;
+        self.failed += rhs.failed;
+        self.deferred += rhs.deferred;
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} synced, {} failed, {} deferred",
+            self.synced, self.failed, self.deferred
+        )
+    }
+}
+
+fn sync_pending(
+    db: &Database,
+    lock: &SyncLock,
+    tracker: &dyn Tracker,
+    pending: SyncContext,
+    force: bool,
+) -> Result<Outcome> {
+    let name = tracker.name();
+    log::debug!("sync to {name} started (force: {force})");
+    let policy = db.conflict_policy()?;
+    let mut outcome = Outcome::default();
+
+    for mut anime in pending {
+        let id = anime.id();
+        lock.renew()?;
+        let res = match tracker.get_entry(id) {
+            Ok(api::Anime {
+                title,
+                progress,
+                episodes,
+                updated_at,
+                status,
+                repeat,
+            }) => {
+                if let Some(ref title) = title
+                    && let Err(err) = db.set_title(id, title)
+                {
+                    show_error(err);
+                }
+                if let Some(episode) = anime.rewatch_candidate() {
+                    if matches!(
+                        status,
+                        Some(MediaListStatus::Completed | MediaListStatus::Repeating)
+                    ) {
+                        anime.start_rewatch()?;
+                        log::info!("started a rewatch of {id} at episode {episode}");
                     } else {
-                        Some(anime.episode())
+                        anime.drop_rewatch_candidate()?;
                     }
+                }
+                let local = anime.episode();
+                let decision = if anime.is_override() {
+                    Decision::Local
+                } else if anime.is_list_only() {
+                    // nothing scrobbled, the remote progress is kept
+                    Decision::Remote
+                } else if conflict::is_conflict(local, progress, updated_at, anime.queued_at()) {
+                    let decision = conflict::resolve(policy, local, progress, || {
+                        ask_conflict(id, title.as_deref(), local, progress)
+                    });
+                    match db.record_conflict(&Conflict {
+                        id,
+                        tracker: name.to_string(),
+                        local,
+                        remote: progress,
+                        remote_updated_at: updated_at,
+                        policy,
+                        decision,
+                        at: database::now(),
+                    }) {
+                        Ok(true) => log::info!(
+                            "conflict on {id} with {name}: local {local}, remote {progress}, {policy} chose {decision}"
+                        ),
+                        Ok(false) => (),
+                        Err(err) => show_error(err),
+                    }
+                    decision
+                } else if local > progress {
+                    Decision::Local
                 } else {
-                    Some(progress)
+                    Decision::Remote
                 };
-                if let Some(episode) = episode {
-                    match anime.update(episode) {
-                        Ok(_) => (),
-                        Err(err) if err.is_fatal() => {
-                            return Err(err.into());
+                let episode = match decision {
+                    Decision::Local => local,
+                    Decision::Remote => progress,
+                    Decision::Deferred => {
+                        outcome.deferred += 1;
+                        continue;
+                    }
+                };
+                let rewatch = db
+                    .rewatch(id)?
+                    .filter(|rewatch| !rewatch.completed || episodes == Some(episode));
+                let change = match rewatch {
+                    Some(rewatch) => {
+                        let mut change = anime.change().cloned().unwrap_or_default();
+                        if episodes == Some(episode) {
+                            change.status = Some(MediaListStatus::Completed);
+                            // the tracker may have seen the end of this rewatch
+                            // already
+                            let counted = status == Some(MediaListStatus::Completed)
+                                && progress == episode
+                                && updated_at.is_some_and(|at| at >= rewatch.started_at);
+                            if !counted {
+                                change.repeat = Some(repeat + 1);
+                            }
+                        } else if change.status.is_none() {
+                            change.status = Some(MediaListStatus::Repeating);
                         }
-                        Err(err) => show_error(err),
+                        Some(change)
                     }
+                    None => anime.change().cloned(),
+                };
+                if episode != progress || change.is_some() {
+                    let saved =
+                        tracker::save_status(change.as_ref(), episode, episodes, progress, status);
+                    let change = ListChange {
+                        status: saved,
+                        ..change.unwrap_or_default()
+                    };
+                    tracker
+                        .save_entry(id, (episode != progress).then_some(episode), &change)
+                        .map(|_| (episode, saved.or(status), rewatch.is_some()))
+                } else {
+                    Ok((progress, status, false))
+                }
+            }
+            Err(err) => Err(err),
+        };
+        let res = match res {
+            Ok((episode, status, rewatch)) => {
+                log::info!("synced {id} to {name} at episode {episode}");
+                outcome.synced += 1;
+                if status == Some(MediaListStatus::Completed) && rewatch {
+                    log::info!("rewatch of {id} completed");
+                    if let Err(err) = db.complete_rewatch(id) {
+                        show_error(err);
+                    }
+                }
+                // kept to tell rewatches apart on the next scrobble
+                if name == "anilist"
+                    && let Some(status) = status
+                    && let Err(err) = db.set_list_status(id, status)
+                {
+                    show_error(err);
                 }
+                anime.update(episode)
+            }
+            Err(err) => {
+                show_error(err);
+                outcome.failed += 1;
+                anime.failed().map(|retry| {
+                    log::warning!(
+                        "sync of {id} to {name} failed {} times, retrying at {}",
+                        retry.attempts,
+                        log::timestamp(retry.next_attempt)
+                    );
+                })
+            }
+        };
+        match res {
+            Ok(_) => (),
+            Err(err) if err.is_fatal() => {
+                return Err(err.into());
             }
             Err(err) => show_error(err),
         }
     }
 
-    sync.commit()?;
-    Ok(None)
+    log::debug!("sync to {name} finished");
+    Ok(outcome)
+}
+
+/// Pushes the pending reading progress to AniList. Progress is only moved
+/// forward, chapters and volumes separately.
+fn sync_manga(db: &Database, lock: &SyncLock, user: &User, force: bool) -> Result<Outcome> {
+    let api = Api::new();
+    let mut outcome = Outcome::default();
+    for (id, reading) in db.sync_manga(force)? {
+        lock.renew()?;
+        let res = api.get_manga(&user.token, user.id, id).and_then(|manga| {
+            let chapter = reading.chapter.max(manga.progress);
+            let volume = match (reading.volume, manga.progress_volumes) {
+                (Some(local), Some(remote)) => Some(local.max(remote)),
+                (local, remote) => local.or(remote),
+            };
+            if chapter == manga.progress && volume == manga.progress_volumes {
+                return Ok(Reading { chapter, volume });
+            }
+            api.set_manga_progress(&user.token, id, chapter, volume, &manga)
+                .map(|(chapter, volume)| Reading { chapter, volume })
+        });
+        let res = match res {
+            Ok(remote) => {
+                log::info!("synced manga {id} at chapter {}", remote.chapter);
+                outcome.synced += 1;
+                db.manga_synced(id, reading, remote)
+            }
+            Err(err) => {
+                show_error(err);
+                outcome.failed += 1;
+                db.manga_failed(id).map(|retry| {
+                    log::warning!(
+                        "sync of manga {id} failed {} times, retrying at {}",
+                        retry.attempts,
+                        log::timestamp(retry.next_attempt)
+                    );
+                })
+            }
+        };
+        match res {
+            Ok(_) => (),
+            Err(err) if err.is_fatal() => {
+                return Err(err.into());
+            }
+            Err(err) => show_error(err),
+        }
+    }
+    Ok(outcome)
+}
+
+/// Asks on the terminal how to settle a conflict, if there is one.
+fn ask_conflict(id: u64, title: Option<&str>, local: u64, remote: u64) -> Option<Decision> {
+    use std::io::IsTerminal;
+
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    let mut answer = String::new();
+    loop {
+        print!(
+            "{}{id}: local episode {local}, remote episode {remote}. Keep [l]ocal, [r]emote or [s]kip? ",
+            title.map(|t| format!("{t} - ")).unwrap_or_default()
+        );
+        std::io::stdout().flush().ok()?;
+        answer.clear();
+        if std::io::stdin().read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        match answer.trim() {
+            "l" | "local" => return Some(Decision::Local),
+            "r" | "remote" => return Some(Decision::Remote),
+            "s" | "skip" => return None,
+            _ => (),
+        }
+    }
 }
 
 fn scrobble(
     anilist_id: u64,
     episode: u64,
+    force: bool,
+    group: Option<String>,
+    entry: EntryArgs,
     background: bool,
     local_only: bool,
 ) -> Result<Option<Cli>> {
-    {
-        let db = Database::new()?;
-        db.scrobble(anilist_id, episode)?;
-        if local_only {
-            return Ok(None);
+    let db = Database::new()?;
+    let change = list_change(&db, entry)?;
+    let episode = tracker::check_episode(&db, anilist_id, episode, force, !local_only)?;
+
+    #[cfg(not(windows))]
+    if !local_only && let Some(mut client) = control::Client::connect()? {
+        client.request(&control::Request::Scrobble {
+            anilist_id,
+            episode,
+            force,
+            group,
+            change,
+        })?;
+        return Ok(None);
+    }
+
+    let accounts = match group {
+        Some(group) => match db.group(&group)? {
+            Some(accounts) => accounts,
+            None => bail!("unknown group {group}"),
+        },
+        None => Vec::new(),
+    };
+    if db.scrobble(anilist_id, episode, force, change.as_ref(), &accounts)? {
+        log::info!("started a rewatch of {anilist_id}");
+    }
+    log::info!("scrobbled {anilist_id} episode {episode} (force: {force})");
+    sync_after(db, background, local_only)
+}
+
+fn rate(
+    anilist_id: u64,
+    entry: EntryArgs,
+    background: bool,
+    local_only: bool,
+) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    // never empty, the score is required
+    let change = list_change(&db, entry)?.unwrap_or_default();
+
+    #[cfg(not(windows))]
+    if !local_only && let Some(mut client) = control::Client::connect()? {
+        client.request(&control::Request::Rate { anilist_id, change })?;
+        return Ok(None);
+    }
+
+    db.queue_change(anilist_id, &change)?;
+    log::info!("queued list change for {anilist_id}");
+    sync_after(db, background, local_only)
+}
+
+/// The user's score format, fetched from AniList the first time.
+fn score_format(db: &Database) -> Result<ScoreFormat> {
+    if let Some(format) = db.score_format()? {
+        return Ok(format);
+    }
+    let Some(user) = db
+        .login()
+        .context("cannot read login, try `aniscrobble db repair`")?
+    else {
+        bail!("login not found")
+    };
+    let format = Api::new()
+        .score_format(&user.token)
+        .context("cannot fetch the score format")?;
+    db.set_score_format(format)?;
+    Ok(format)
+}
+
+/// Builds the list change asked on the command line, `None` if nothing is.
+/// The score is read in the user's score format.
+fn list_change(db: &Database, entry: EntryArgs) -> Result<Option<ListChange>> {
+    let score = match entry.score {
+        Some(score) => {
+            let format = score_format(db)?;
+            match format.normalize(score) {
+                Some(score) => Some(score),
+                None => bail!("invalid score {score}, the score format is {format}"),
+            }
+        }
+        None => None,
+    };
+    let change = ListChange {
+        score,
+        notes: entry.notes,
+        custom_lists: (!entry.custom_lists.is_empty()).then_some(entry.custom_lists),
+        private: entry.private,
+        hidden: entry.hidden,
+        ..Default::default()
+    };
+    Ok((change.score.is_some()
+        || change.notes.is_some()
+        || change.custom_lists.is_some()
+        || change.private.is_some()
+        || change.hidden.is_some())
+    .then_some(change))
+}
+
+fn read(
+    manga_id: u64,
+    chapter: u64,
+    volume: Option<u64>,
+    background: bool,
+    local_only: bool,
+) -> Result<Option<Cli>> {
+    #[cfg(not(windows))]
+    if !local_only && let Some(mut client) = control::Client::connect()? {
+        client.request(&control::Request::Read {
+            manga_id,
+            chapter,
+            volume,
+        })?;
+        return Ok(None);
+    }
+
+    let db = Database::new()?;
+    if db.read(manga_id, chapter, volume)? {
+        log::info!("read {manga_id} chapter {chapter}");
+    }
+    sync_after(db, background, local_only)
+}
+
+fn conflicts(command: Option<ConflictsCommands>) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    match command.unwrap_or(ConflictsCommands::List { json: false }) {
+        ConflictsCommands::List { json } => {
+            let conflicts = db.conflicts()?;
+            if json {
+                serde_json::to_writer_pretty(std::io::stdout().lock(), &conflicts)?;
+                println!();
+                return Ok(None);
+            }
+            if conflicts.is_empty() {
+                println!("no conflicts");
+                return Ok(None);
+            }
+            println!(
+                "{:<20}  {:<12}  {:>8}  {:>5}  {:>6}  {:<20}  {:<12}  DECISION",
+                "AT", "TRACKER", "ID", "LOCAL", "REMOTE", "REMOTE UPDATED", "POLICY"
+            );
+            for conflict in conflicts {
+                println!(
+                    "{:<20}  {:<12}  {:>8}  {:>5}  {:>6}  {:<20}  {:<12}  {}",
+                    log::timestamp(conflict.at),
+                    conflict.tracker,
+                    conflict.id,
+                    conflict.local,
+                    conflict.remote,
+                    conflict
+                        .remote_updated_at
+                        .map(log::timestamp)
+                        .unwrap_or_default(),
+                    conflict.policy.to_string(),
+                    conflict.decision
+                );
+            }
         }
-        if !background {
-            return sync(Some(db));
+        ConflictsCommands::Policy { policy: None } => println!("{}", db.conflict_policy()?),
+        ConflictsCommands::Policy {
+            policy: Some(policy),
+        } => {
+            db.set_conflict_policy(policy)?;
+            log::info!("conflict policy set to {policy}");
         }
+        ConflictsCommands::Clear => db.clear_conflicts()?,
     }
+    Ok(None)
+}
+
+fn pull() -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    let Some(user) = db
+        .login()
+        .context("cannot read login, try `aniscrobble db repair`")?
+    else {
+        bail!("login not found")
+    };
+    let entries = Api::new().get_list(&user.token, user.id)?;
+    let report = db.pull(&entries)?;
+    log::info!(
+        "pulled {} entries, {} updated, {} conflicts",
+        entries.len(),
+        report.updated,
+        report.conflicts.len()
+    );
+
+    println!("{} entries, {} updated", entries.len(), report.updated);
+    let policy = db.conflict_policy()?;
+    for conflict in report.conflicts {
+        // settled by the next sync
+        db.record_conflict(&Conflict {
+            id: conflict.id,
+            tracker: "anilist".to_string(),
+            local: conflict.local,
+            remote: conflict.remote,
+            remote_updated_at: conflict.remote_updated_at,
+            policy,
+            decision: Decision::Deferred,
+            at: database::now(),
+        })?;
+        println!(
+            "conflict on {}: local episode {}, remote episode {}{}",
+            conflict.id,
+            conflict.local,
+            conflict.remote,
+            conflict
+                .remote_updated_at
+                .map(|at| format!(" (updated {})", log::timestamp(at)))
+                .unwrap_or_default()
+        );
+    }
+    Ok(None)
+}
+
+fn export(
+    format: export::Format,
+    output: Option<PathBuf>,
+    with_token: bool,
+) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    let dump = db
+        .export(with_token)
+        .context("cannot read database, try `aniscrobble db repair`")?;
+    match output {
+        Some(path) => {
+            let file = std::fs::File::create(&path)
+                .with_context(|| format!("cannot create {}", path.display()))?;
+            let mut w = std::io::BufWriter::new(file);
+            export::write(&dump, format, &mut w)?;
+            w.flush()?;
+        }
+        None => export::write(&dump, format, std::io::stdout().lock())?,
+    }
+    Ok(None)
+}
+
+fn export_mal(output: Option<PathBuf>) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    let Some(user) = db
+        .login()
+        .context("cannot read login, try `aniscrobble db repair`")?
+    else {
+        bail!("login not found")
+    };
+    let entries = Api::new().get_list(&user.token, user.id)?;
+    let skipped = match output {
+        Some(path) => {
+            let file = std::fs::File::create(&path)
+                .with_context(|| format!("cannot create {}", path.display()))?;
+            let mut w = std::io::BufWriter::new(file);
+            let skipped = mal::write(&entries, &mut w)?;
+            w.flush()?;
+            skipped
+        }
+        None => mal::write(&entries, std::io::stdout().lock())?,
+    };
+    for entry in skipped {
+        eprintln!(
+            "no MyAnimeList entry for {}{}",
+            entry.media_id,
+            entry
+                .media
+                .as_ref()
+                .and_then(|media| media.title.userPreferred.as_deref())
+                .map(|title| format!(" ({title})"))
+                .unwrap_or_default()
+        );
+    }
+    Ok(None)
+}
+
+fn import(format: Option<export::Format>, file: PathBuf) -> Result<Option<Cli>> {
+    let format = format.unwrap_or_else(|| export::Format::from_path(&file));
+    let r = std::fs::File::open(&file)
+        .map(std::io::BufReader::new)
+        .with_context(|| format!("cannot open {}", file.display()))?;
+    let dump =
+        export::read(format, r).with_context(|| format!("cannot read {}", file.display()))?;
+
+    let db = Database::new()?;
+    let report = db.import(&dump)?;
+    log::info!(
+        "imported {}: {} pending, {} progress, {} history entries, {} reading",
+        file.display(),
+        report.pending,
+        report.progress,
+        report.history,
+        report.reading
+    );
+    if report.login {
+        println!("login imported");
+    }
+    println!(
+        "{} pending, {} progress, {} history entries, {} reading imported",
+        report.pending, report.progress, report.history, report.reading
+    );
+    Ok(None)
+}
+
+fn import_mal(file: PathBuf, dry_run: bool) -> Result<Option<Cli>> {
+    let r = std::fs::File::open(&file)
+        .map(std::io::BufReader::new)
+        .with_context(|| format!("cannot open {}", file.display()))?;
+    let entries = mal::parse(r).with_context(|| format!("cannot read {}", file.display()))?;
+
+    let mal_ids = entries
+        .iter()
+        .map(|entry| entry.series_animedb_id)
+        .collect::<Vec<_>>();
+    let mut ids = Api::new().get_ids_by_mal(&mal_ids)?;
+    ids.sort_unstable_by_key(|(mal_id, ..)| *mal_id);
+
+    if dry_run {
+        println!(
+            "{:>8}  {:>8}  {:>8}  {:<10}  TITLE",
+            "MAL", "ID", "EPISODE", "STATUS"
+        );
+    }
+    let mut queued = Vec::new();
+    let mut unmapped = Vec::new();
+    for entry in &entries {
+        let Ok(i) = ids.binary_search_by_key(&entry.series_animedb_id, |(mal_id, ..)| *mal_id)
+        else {
+            unmapped.push(entry);
+            continue;
+        };
+        let (_, id, title) = &ids[i];
+        let change = QueuedChange {
+            id: *id,
+            title: title.clone(),
+            episode: entry.my_watched_episodes,
+            change: entry.change(),
+        };
+        if dry_run {
+            println!(
+                "{:>8}  {:>8}  {:>8}  {:<10}  {}",
+                entry.series_animedb_id,
+                change.id,
+                change.episode,
+                change
+                    .change
+                    .status
+                    .map(|status| status.to_string())
+                    .unwrap_or_default(),
+                change.title.as_deref().unwrap_or(&entry.series_title)
+            );
+        }
+        queued.push(change);
+    }
+
+    if !dry_run {
+        Database::new()?.queue_changes(&queued)?;
+        log::info!(
+            "imported {}: {} queued, {} unmapped",
+            file.display(),
+            queued.len(),
+            unmapped.len()
+        );
+    }
+
+    for entry in &unmapped {
+        println!(
+            "no AniList entry for {} ({})",
+            entry.series_animedb_id, entry.series_title
+        );
+    }
+    println!(
+        "{} {}, {} unmapped",
+        queued.len(),
+        if dry_run { "to queue" } else { "queued" },
+        unmapped.len()
+    );
+    Ok(None)
+}
+
+fn undo(count: usize, background: bool, local_only: bool) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    let entries = db.undo(count)?;
+    if entries.is_empty() {
+        println!("nothing to undo");
+        return Ok(None);
+    }
+    for entry in &entries {
+        let previous = entry
+            .previous
+            .map(|ep| ep.to_string())
+            .unwrap_or_else(|| "none".to_string());
+        println!("{}: episode {} -> {previous}", entry.id, entry.episode);
+        log::info!("undid {} episode {} -> {previous}", entry.id, entry.episode);
+    }
+    sync_after(db, background, local_only)
+}
+
+/// Syncs after a local change, through the daemon if one is running.
+fn sync_after(db: Database, background: bool, local_only: bool) -> Result<Option<Cli>> {
+    if local_only {
+        return Ok(None);
+    }
+
+    #[cfg(not(windows))]
+    if let Some(mut client) = control::Client::connect()? {
+        client.request(&control::Request::Sync)?;
+        return Ok(None);
+    }
+
+    if !background {
+        return sync(Some(db));
+    }
+    drop(db);
 
     #[cfg(windows)]
     {
@@ -155,7 +1243,12 @@ fn scrobble(
         const CREATE_NO_WINDOW: u32 = 0x8000000;
         const DETACHED_PROCESS: u32 = 8;
 
-        std::process::Command::new(std::env::current_exe().context("Cannot spawn sync task")?)
+        let mut command =
+            std::process::Command::new(std::env::current_exe().context("Cannot spawn sync task")?);
+        if let Some(db) = paths::db_override() {
+            command.arg("--db").arg(db);
+        }
+        command
             .arg("sync")
             .stderr(std::process::Stdio::null())
             .stdout(std::process::Stdio::null())
@@ -170,6 +1263,7 @@ fn scrobble(
     {
         match unsafe { daemon::daemonize()? } {
             daemon::Whoami::Child => Ok(Some(Cli {
+                db: None,
                 command: Commands::Sync,
             })),
 
@@ -184,18 +1278,436 @@ fn scrobble(
     }
 }
 
-const TOKEN_URL: &str =
-    "https://anilist.co/api/v2/oauth/authorize?client_id=7723&response_type=token";
+#[cfg(not(windows))]
+fn daemon(background: bool) -> Result<Option<Cli>> {
+    if control::Client::connect()?.is_some() {
+        bail!("daemon already running");
+    }
+
+    if background {
+        match unsafe { daemon::daemonize()? } {
+            daemon::Whoami::Child => (),
+            daemon::Whoami::Parent(true) => return Ok(None),
+            daemon::Whoami::Parent(false) => bail!("The process could not be cloned"),
+        }
+    }
+
+    server::run(Database::new()?)?;
+    Ok(None)
+}
+
+#[cfg(not(windows))]
+fn status() -> Result<Option<Cli>> {
+    let Some(mut client) = control::Client::connect()? else {
+        println!("daemon: not running");
+        let db = Database::new()?;
+        println!("pending: {}", db.pending_len()?);
+        println!("manga pending: {}", db.manga_pending_len()?);
+        return Ok(None);
+    };
+    let control::Response::Status(status) = client.request(&control::Request::Status)? else {
+        bail!("invalid response from daemon");
+    };
+
+    println!("daemon: running");
+    println!("pending: {}", status.pending);
+    println!("manga pending: {}", status.manga_pending);
+    if status.syncing {
+        println!("syncing: yes");
+    }
+    if let Some(secs) = status.last_sync {
+        println!("last sync: {secs}s ago");
+    }
+    if let Some(err) = status.last_error {
+        println!("last error: {err}");
+    }
+    if let Some(secs) = status.next_retry {
+        println!("next retry: in {secs}s");
+    }
+    Ok(None)
+}
+
+fn pending(command: PendingCommands) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    match command {
+        PendingCommands::List { json } => {
+            let entries = db.pending_entries()?;
+            if json {
+                serde_json::to_writer_pretty(std::io::stdout().lock(), &entries)?;
+                println!();
+                return Ok(None);
+            }
+            if entries.is_empty() {
+                println!("nothing pending");
+                return Ok(None);
+            }
+            println!("{:>8}  {:>8}  {:<20}  TITLE", "ID", "EPISODE", "QUEUED");
+            for entry in entries {
+                println!(
+                    "{:>8}  {:>7}{}  {:<20}  {}",
+                    entry.id,
+                    entry.episode,
+                    if entry.force { '!' } else { ' ' },
+                    entry.queued_at.map(log::timestamp).unwrap_or_default(),
+                    entry.title.as_deref().unwrap_or("")
+                );
+            }
+        }
+        PendingCommands::Remove { anilist_id } => {
+            if !db.remove_pending(anilist_id)? {
+                bail!("{anilist_id} is not pending");
+            }
+            log::info!("removed {anilist_id} from pending");
+        }
+        PendingCommands::Set {
+            anilist_id,
+            episode,
+        } => {
+            db.set_pending(anilist_id, episode)?;
+            log::info!("set pending {anilist_id} to episode {episode}");
+        }
+        PendingCommands::Clear => {
+            let n = db.clear_pending()?;
+            log::info!("cleared {n} pending scrobbles");
+            println!("cleared {n} pending scrobbles");
+        }
+    }
+    Ok(None)
+}
 
-fn login(force: bool) -> Result<Option<Cli>> {
+fn group(command: Option<GroupCommands>) -> Result<Option<Cli>> {
     let db = Database::new()?;
-    if force {
-        db.delete_login()?;
+    match command.unwrap_or(GroupCommands::List) {
+        GroupCommands::List => {
+            for (name, accounts) in db.groups()? {
+                println!("{name}: {}", accounts.join(", "));
+            }
+        }
+        GroupCommands::Add { group, accounts } => {
+            for account in &accounts {
+                if db
+                    .tracker(&format!("{}{account}", tracker::ACCOUNT_PREFIX))?
+                    .is_none()
+                {
+                    bail!("unknown account {account}, see `aniscrobble login --account`");
+                }
+            }
+            db.add_to_group(&group, &accounts)?;
+        }
+        GroupCommands::Remove { group, accounts } => {
+            if !db.remove_from_group(&group, &accounts)? {
+                bail!("unknown group {group}");
+            }
+        }
     }
+    Ok(None)
+}
 
-    if db.login()?.is_some() {
-        eprintln!("Already logged in");
+fn trackers(command: Option<TrackersCommands>) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    match command.unwrap_or(TrackersCommands::List) {
+        TrackersCommands::List => {
+            println!("{:<10}  {:>10}  {:>8}", "TRACKER", "USER", "PENDING");
+            if let Some(user) = db
+                .login()
+                .context("cannot read login, try `aniscrobble db repair`")?
+            {
+                println!(
+                    "{:<10}  {:>10}  {:>8}",
+                    "anilist",
+                    user.id,
+                    db.pending_len()?
+                );
+            }
+            for (name, credentials) in db.trackers()? {
+                println!(
+                    "{:<10}  {:>10}  {:>8}",
+                    name,
+                    credentials.user_id,
+                    db.outbox_len(&name)?
+                );
+            }
+        }
+        TrackersCommands::Remove { name } if name == "anilist" => {
+            bail!("use `aniscrobble login --force` to replace the AniList login")
+        }
+        TrackersCommands::Remove { name } => {
+            if !db.delete_tracker(&name)? {
+                bail!("{name} is not configured");
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn search(query: String, filter: api::SearchFilter, json: bool) -> Result<Option<Cli>> {
+    let results = Api::new().search(&query, &filter)?;
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), &results)?;
+        println!();
+        return Ok(None);
+    }
+    println!(
+        "{:>8}  {:>8}  {:<16}  {:<8}  TITLE",
+        "ID", "EPISODES", "STATUS", "FORMAT"
+    );
+    for media in results {
+        let mut title = media.title.userPreferred.unwrap_or_default();
+        if let Some(english) = media.title.english
+            && english != title
+        {
+            title = format!("{title} ({english})");
+        }
+        println!(
+            "{:>8}  {:>8}  {:<16}  {:<8}  {}",
+            media.id,
+            media
+                .episodes
+                .map(|episodes| episodes.to_string())
+                .unwrap_or_default(),
+            media
+                .status
+                .map(|status| status.to_string())
+                .unwrap_or_default(),
+            media.format.unwrap_or_default(),
+            title
+        );
+    }
+    Ok(None)
+}
+
+fn search_tracker(kind: TrackerKind, query: String) -> Result<Option<Cli>> {
+    let name = match kind {
+        TrackerKind::Anilist => "anilist",
+        TrackerKind::Mal => "mal",
+        TrackerKind::Kitsu => "kitsu",
+    };
+    let db = Database::new()?;
+    let Some(credentials) = db.tracker(name)? else {
+        bail!("not logged in to {name}, see `aniscrobble login --tracker {name}`")
+    };
+    let Some(tracker) = tracker::open(&db, name, credentials) else {
+        bail!("unknown tracker {name}");
+    };
+    println!("{:>8}  {:>8}  TITLE", "ID", "EPISODES");
+    for result in tracker.search(&query)? {
+        println!(
+            "{:>8}  {:>8}  {}",
+            result.id,
+            result
+                .episodes
+                .map(|episodes| episodes.to_string())
+                .unwrap_or_default(),
+            result.title.unwrap_or_default()
+        );
+    }
+    Ok(None)
+}
+
+fn info(anilist_id: u64, json: bool) -> Result<Option<Cli>> {
+    #[derive(serde::Serialize)]
+    struct Info {
+        id: u64,
+        media: api::MediaInfo,
+        entry: Option<api::MediaList>,
+    }
+
+    let db = Database::new()?;
+    let media = tracker::fetch_media(&db, anilist_id)?;
+    let entry = match db
+        .login()
+        .context("cannot read login, try `aniscrobble db repair`")?
+    {
+        Some(user) => Api::new().get_list_entry(&user.token, user.id, anilist_id)?,
+        None => None,
+    };
+    if json {
+        serde_json::to_writer_pretty(
+            std::io::stdout().lock(),
+            &Info {
+                id: anilist_id,
+                media,
+                entry,
+            },
+        )?;
+        println!();
+        return Ok(None);
+    }
+
+    let show = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            println!("{name:<14}{value}");
+        }
+    };
+    show("title:", media.title);
+    show("format:", media.format);
+    show("status:", media.status.map(|status| status.to_string()));
+    show(
+        "episodes:",
+        media.episodes.map(|episodes| episodes.to_string()),
+    );
+    show(
+        "duration:",
+        media.duration.map(|duration| format!("{duration} min")),
+    );
+    show(
+        "next episode:",
+        media
+            .next_airing
+            .map(|next| format!("{} at {}", next.episode, log::timestamp(next.airing_at))),
+    );
+    show("mal id:", media.id_mal.map(|id| id.to_string()));
+    show("cover:", media.cover);
+    for relation in media.relations {
+        show(
+            "relation:",
+            Some(format!(
+                "{} {}",
+                relation.relation.to_lowercase().replace('_', " "),
+                relation.id
+            )),
+        );
+    }
+    let Some(entry) = entry else {
+        println!("not in the list");
         return Ok(None);
+    };
+    show(
+        "list status:",
+        entry.status.map(|status| status.to_string()),
+    );
+    show(
+        "progress:",
+        entry.progress.map(|progress| progress.to_string()),
+    );
+    let score = match entry.score.filter(|score| *score != 0.0) {
+        Some(score) => Some(score_format(&db)?.denormalize(score).to_string()),
+        None => None,
+    };
+    show("score:", score);
+    show(
+        "rewatched:",
+        entry
+            .repeat
+            .filter(|repeat| *repeat != 0)
+            .map(|repeat| repeat.to_string()),
+    );
+    show("notes:", entry.notes);
+    show("updated:", entry.updated_at.map(log::timestamp));
+    Ok(None)
+}
+
+fn cache(command: CacheCommands) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    match command {
+        CacheCommands::Refresh { all } => {
+            let now = database::now();
+            let api = Api::new();
+            let (mut refreshed, mut failed) = (0, 0);
+            for (id, media) in db.cached_media()? {
+                if !all && media.is_fresh(now) {
+                    continue;
+                }
+                match api.get_media(id) {
+                    Ok(info) => {
+                        db.set_media(id, &info)?;
+                        refreshed += 1;
+                    }
+                    Err(err) => {
+                        show_error(err);
+                        failed += 1;
+                    }
+                }
+            }
+            println!("{refreshed} refreshed, {failed} failed");
+        }
+        CacheCommands::Clear => println!("{} removed", db.clear_media()?),
+    }
+    Ok(None)
+}
+
+fn db(command: DbCommands) -> Result<Option<Cli>> {
+    let repair = matches!(command, DbCommands::Repair);
+    let db = Database::new_unchecked()?;
+    let issues = db.check(repair)?;
+    for issue in &issues {
+        println!("{issue}");
+        if repair {
+            log::info!("db repair: {issue}");
+        }
+    }
+    if issues.is_empty() {
+        println!("no issues found");
+    } else if !repair {
+        println!("run `aniscrobble db repair` to fix them");
+    }
+    let corrupt = db.corrupt_len()?;
+    if corrupt > 0 {
+        println!("{corrupt} records in quarantine");
+    }
+    Ok(None)
+}
+
+const TOKEN_URL: &str =
+    "https://anilist.co/api/v2/oauth/authorize?client_id=7723&response_type=token";
+
+/// Reads a non empty line from the terminal, exiting on end of input.
+fn prompt(prompt: &str) -> Result<String> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        print!("{prompt}> ");
+        std::io::stdout().flush()?;
+        std::io::stdin().read_line(&mut line)?;
+        if line.is_empty() {
+            std::process::exit(1);
+        }
+        if line.strip_suffix('\n').is_some() {
+            line.remove(line.len() - 1);
+            if line.strip_suffix('\r').is_some() {
+                line.remove(line.len() - 1);
+            }
+        } else if line.strip_suffix("\n\r").is_some() {
+            line.remove(line.len() - 1);
+            line.remove(line.len() - 1);
+        }
+        if !line.is_empty() {
+            return Ok(line);
+        }
+    }
+}
+
+fn login(
+    force: bool,
+    tracker: TrackerKind,
+    client_id: Option<String>,
+    account: Option<String>,
+) -> Result<Option<Cli>> {
+    match tracker {
+        TrackerKind::Anilist => login_anilist(force, account),
+        TrackerKind::Mal => login_mal(force, client_id),
+        TrackerKind::Kitsu => login_kitsu(force),
+    }
+}
+
+fn login_anilist(force: bool, account: Option<String>) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    let name = account.map(|account| format!("{}{account}", tracker::ACCOUNT_PREFIX));
+    match &name {
+        Some(name) if !force && db.tracker(name)?.is_some() => {
+            eprintln!("Already logged in");
+            return Ok(None);
+        }
+        Some(_) => (),
+        None => {
+            if force {
+                db.delete_login()?;
+            }
+            if db.login()?.is_some() {
+                eprintln!("Already logged in");
+                return Ok(None);
+            }
+        }
     }
 
     if open::that(TOKEN_URL).is_err() {
@@ -204,33 +1716,106 @@ fn login(force: bool) -> Result<Option<Cli>> {
         println!("Paste here the token from your browser or manually open {TOKEN_URL}.")
     }
 
-    let mut token = String::new();
-    let api = Api::new();
     loop {
-        loop {
-            token.clear();
-            print!("token> ");
-            std::io::stdout().flush()?;
-            std::io::stdin().read_line(&mut token)?;
-            if token.is_empty() {
-                std::process::exit(1);
-            }
-            if token.strip_suffix('\n').is_some() {
-                token.remove(token.len() - 1);
-                if token.strip_suffix('\r').is_some() {
-                    token.remove(token.len() - 1);
+        let token = prompt("token")?;
+        let anilist = tracker::AniList::new(
+            db.clone(),
+            User {
+                token: token.clone(),
+                id: 0,
+            },
+        );
+        match anilist.me().context("invalid token") {
+            Ok(id) => {
+                match &name {
+                    Some(name) => db.set_tracker(
+                        name,
+                        &Credentials {
+                            user_id: id,
+                            token,
+                            refresh_token: None,
+                            expires_at: None,
+                            client_id: None,
+                        },
+                    )?,
+                    None => db.set_login(User { token, id })?,
                 }
-            } else if token.strip_suffix("\n\r").is_some() {
-                token.remove(token.len() - 1);
-                token.remove(token.len() - 1);
+                return Ok(None);
             }
-            if !token.is_empty() {
-                break;
+            Err(err) => show_error(err),
+        }
+    }
+}
+
+fn login_kitsu(force: bool) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    if !force && db.tracker("kitsu")?.is_some() {
+        eprintln!("Already logged in");
+        return Ok(None);
+    }
+
+    loop {
+        let username = prompt("email")?;
+        let password = rpassword::prompt_password("password> ")?;
+        let res = tracker::kitsu::login(tracker::kitsu::OAUTH, &username, &password).and_then(
+            |credentials| {
+                let kitsu = tracker::Kitsu::new(db.clone(), credentials.clone());
+                kitsu.me().map(|user_id| Credentials {
+                    user_id,
+                    ..credentials
+                })
+            },
+        );
+        match res.context("invalid email or password") {
+            Ok(credentials) => {
+                db.set_tracker("kitsu", &credentials)?;
+                return Ok(None);
             }
+            Err(err) => show_error(err),
         }
-        match api.me(&token).context("invalid token") {
-            Ok(id) => {
-                db.set_login(User { token, id })?;
+    }
+}
+
+fn login_mal(force: bool, client_id: Option<String>) -> Result<Option<Cli>> {
+    let db = Database::new()?;
+    if !force && db.tracker("mal")?.is_some() {
+        eprintln!("Already logged in");
+        return Ok(None);
+    }
+    let Some(client_id) = client_id else {
+        bail!(
+            "a MyAnimeList client id is needed, pass --client-id or set ANISCROBBLE_MAL_CLIENT_ID"
+        )
+    };
+
+    let verifier = tracker::mal::new_verifier().context("cannot generate a code verifier")?;
+    let url = tracker::mal::authorize_url(&client_id, &verifier);
+    if open::that(&url).is_err() {
+        println!(
+            "Please open {url} in your browser and paste the address you are redirected to here."
+        )
+    } else {
+        println!("Paste here the address your browser is redirected to or manually open {url}.")
+    }
+
+    loop {
+        let line = prompt("code")?;
+        // either the bare code or the whole redirect address
+        let code = line
+            .split(['?', '&'])
+            .find_map(|param| param.strip_prefix("code="))
+            .unwrap_or(&line);
+        let res = tracker::mal::exchange_code(tracker::mal::OAUTH, &client_id, code, &verifier)
+            .and_then(|credentials| {
+                let mal = tracker::Mal::new(db.clone(), credentials.clone());
+                mal.me().map(|user_id| Credentials {
+                    user_id,
+                    ..credentials
+                })
+            });
+        match res.context("invalid code") {
+            Ok(credentials) => {
+                db.set_tracker("mal", &credentials)?;
                 return Ok(None);
             }
             Err(err) => show_error(err),
@@ -0,0 +1,210 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::api::{FuzzyDate, ListChange, MediaList, MediaListStatus};
+
+#[derive(Debug, Deserialize)]
+struct MyAnimeList {
+    #[serde(default)]
+    anime: Vec<MalAnime>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MalAnime {
+    pub series_animedb_id: u64,
+    #[serde(default)]
+    pub series_title: String,
+    #[serde(default)]
+    pub my_watched_episodes: u64,
+    #[serde(default, deserialize_with = "date")]
+    pub my_start_date: Option<FuzzyDate>,
+    #[serde(default, deserialize_with = "date")]
+    pub my_finish_date: Option<FuzzyDate>,
+    #[serde(default)]
+    pub my_score: u64,
+    #[serde(default)]
+    pub my_status: String,
+    #[serde(default)]
+    pub my_times_watched: u64,
+    #[serde(default, deserialize_with = "flag")]
+    pub my_rewatching: bool,
+}
+
+/// MAL writes unknown date parts as zeroes, like `2020-04-00`.
+fn date<'de, D: Deserializer<'de>>(de: D) -> Result<Option<FuzzyDate>, D::Error> {
+    let s = String::deserialize(de)?;
+    let mut parts = s
+        .trim()
+        .splitn(3, '-')
+        .map(|part| part.parse::<u32>().ok().filter(|&n| n != 0));
+    let date = FuzzyDate {
+        year: parts.next().flatten(),
+        month: parts.next().flatten(),
+        day: parts.next().flatten(),
+    };
+    Ok((!date.is_empty()).then_some(date))
+}
+
+fn flag<'de, D: Deserializer<'de>>(de: D) -> Result<bool, D::Error> {
+    let s = String::deserialize(de)?;
+    Ok(s.trim() == "1")
+}
+
+impl MalAnime {
+    pub fn status(&self) -> Option<MediaListStatus> {
+        if self.my_rewatching {
+            return Some(MediaListStatus::Repeating);
+        }
+        // older exports use the numeric codes
+        match self.my_status.trim() {
+            "Watching" | "1" => Some(MediaListStatus::Current),
+            "Completed" | "2" => Some(MediaListStatus::Completed),
+            "On-Hold" | "3" => Some(MediaListStatus::Paused),
+            "Dropped" | "4" => Some(MediaListStatus::Dropped),
+            "Plan to Watch" | "6" => Some(MediaListStatus::Planning),
+            _ => None,
+        }
+    }
+
+    pub fn change(&self) -> ListChange {
+        ListChange {
+            status: self.status(),
+            // 0 means not scored
+            score: (self.my_score != 0).then_some(self.my_score as f64),
+            started_at: self.my_start_date,
+            completed_at: self.my_finish_date,
+            repeat: (self.my_times_watched != 0).then_some(self.my_times_watched),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn parse(r: impl BufRead) -> Result<Vec<MalAnime>> {
+    let list: MyAnimeList = quick_xml::de::from_reader(r).context("invalid MyAnimeList export")?;
+    Ok(list.anime)
+}
+
+fn format_date(date: Option<FuzzyDate>) -> String {
+    let date = date.unwrap_or_default();
+    format!(
+        "{:04}-{:02}-{:02}",
+        date.year.unwrap_or(0),
+        date.month.unwrap_or(0),
+        date.day.unwrap_or(0)
+    )
+}
+
+fn cdata(s: &str) -> String {
+    format!("<![CDATA[{}]]>", s.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+/// Writes `entries` as a MyAnimeList XML export, returning the entries left
+/// out because AniList does not know their MyAnimeList id.
+pub fn write(entries: &[MediaList], mut w: impl Write) -> Result<Vec<&MediaList>> {
+    let mut skipped = Vec::new();
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8" ?>"#)?;
+    writeln!(w, "<myanimelist>")?;
+    writeln!(w, "  <myinfo>")?;
+    writeln!(w, "    <user_export_type>1</user_export_type>")?;
+    writeln!(w, "  </myinfo>")?;
+    for entry in entries {
+        let Some(media) = &entry.media else {
+            skipped.push(entry);
+            continue;
+        };
+        let Some(id_mal) = media.id_mal else {
+            skipped.push(entry);
+            continue;
+        };
+        let status = match entry.status {
+            Some(MediaListStatus::Current) => "Watching",
+            Some(MediaListStatus::Completed | MediaListStatus::Repeating) => "Completed",
+            Some(MediaListStatus::Paused) => "On-Hold",
+            Some(MediaListStatus::Dropped) => "Dropped",
+            Some(MediaListStatus::Planning) | None => "Plan to Watch",
+        };
+        writeln!(w, "  <anime>")?;
+        writeln!(w, "    <series_animedb_id>{id_mal}</series_animedb_id>")?;
+        writeln!(
+            w,
+            "    <series_title>{}</series_title>",
+            cdata(media.title.userPreferred.as_deref().unwrap_or(""))
+        )?;
+        writeln!(
+            w,
+            "    <series_episodes>{}</series_episodes>",
+            media.episodes.unwrap_or(0)
+        )?;
+        writeln!(
+            w,
+            "    <my_watched_episodes>{}</my_watched_episodes>",
+            entry.progress.unwrap_or(0)
+        )?;
+        writeln!(
+            w,
+            "    <my_start_date>{}</my_start_date>",
+            format_date(entry.started_at)
+        )?;
+        writeln!(
+            w,
+            "    <my_finish_date>{}</my_finish_date>",
+            format_date(entry.completed_at)
+        )?;
+        writeln!(
+            w,
+            "    <my_score>{}</my_score>",
+            entry.score.unwrap_or(0.0).round() as u64
+        )?;
+        writeln!(w, "    <my_status>{status}</my_status>")?;
+        writeln!(
+            w,
+            "    <my_times_watched>{}</my_times_watched>",
+            entry.repeat.unwrap_or(0)
+        )?;
+        writeln!(
+            w,
+            "    <my_rewatching>{}</my_rewatching>",
+            u8::from(entry.status == Some(MediaListStatus::Repeating))
+        )?;
+        writeln!(w, "    <update_on_import>1</update_on_import>")?;
+        writeln!(w, "  </anime>")?;
+    }
+    writeln!(w, "</myanimelist>")?;
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::Api, tracker::fake::FakeServer};
+
+    #[test]
+    fn write_matches_golden_file() {
+        let server = FakeServer::start(vec![(
+            200,
+            include_str!("../testdata/mal/collection.json").to_string(),
+        )]);
+        let entries = Api::with_url(&server.url).get_list("token", 42).unwrap();
+        // the custom list repeats an entry of the status lists
+        let ids: Vec<_> = entries.iter().map(|entry| entry.media_id).collect();
+        assert_eq!(ids, [1, 21, 30, 5114, 999999]);
+        let requests = server.finish();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].header("authorization"), Some("Bearer token"));
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["variables"]["userId"], 42);
+
+        let mut xml = Vec::new();
+        let skipped = super::write(&entries, &mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert_eq!(xml, include_str!("../testdata/mal/export.xml"));
+        let skipped: Vec<_> = skipped.iter().map(|entry| entry.media_id).collect();
+        assert_eq!(skipped, [999999]);
+
+        // and it reads back
+        let parsed = super::parse(xml.as_bytes()).unwrap();
+        assert_eq!(parsed[3].series_title, "Tricky ]]> <Title> & more");
+        assert_eq!(parsed[1].change().status, entries[1].status);
+    }
+}
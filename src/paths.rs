@@ -0,0 +1,118 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+
+/// Marker file that, next to the executable, enables portable mode.
+const PORTABLE_MARKER: &str = "aniscrobble.portable";
+
+static DB_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn project_dirs() -> directories::ProjectDirs {
+    directories::ProjectDirs::from("dev", "shurizzle", "aniscrobble").unwrap()
+}
+
+/// Uses `path` as database instead of the default one, for the whole
+/// process.
+pub fn set_db_override(path: PathBuf) {
+    _ = DB_OVERRIDE.set(path);
+}
+
+pub fn db_override() -> Option<&'static Path> {
+    DB_OVERRIDE.get().map(PathBuf::as_path)
+}
+
+/// Directory of the executable, if it is marked as portable.
+pub fn portable_dir() -> Option<PathBuf> {
+    static DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let exe = std::env::current_exe().ok()?;
+        let dir = exe.parent()?;
+        dir.join(PORTABLE_MARKER)
+            .exists()
+            .then(|| dir.to_path_buf())
+    })
+    .clone()
+}
+
+/// Directory for durable data.
+pub fn data_dir() -> PathBuf {
+    portable_dir().unwrap_or_else(|| project_dirs().data_dir().to_path_buf())
+}
+
+/// Directory for logs.
+pub fn state_dir() -> PathBuf {
+    portable_dir().unwrap_or_else(|| {
+        let dirs = project_dirs();
+        dirs.state_dir()
+            .unwrap_or_else(|| dirs.data_local_dir())
+            .to_path_buf()
+    })
+}
+
+/// The log file, kept next to the database when it is not the default one,
+/// like the daemon socket.
+pub fn log_path() -> PathBuf {
+    if db_override().is_some() {
+        let mut path = db_path().into_os_string();
+        path.push(".log");
+        return path.into();
+    }
+    state_dir().join("aniscrobble.log")
+}
+
+pub fn db_path() -> PathBuf {
+    if let Some(path) = db_override() {
+        return path.to_path_buf();
+    }
+    data_dir().join("data.db")
+}
+
+/// Where the database lived before it was moved to the data dir.
+fn legacy_db_path() -> PathBuf {
+    project_dirs().cache_dir().join("data.db")
+}
+
+/// The daemon socket, kept next to the database when it is not the default
+/// one so that daemons serving different databases do not collide.
+pub fn socket_path() -> PathBuf {
+    if db_override().is_some() || portable_dir().is_some() {
+        let mut path = db_path().into_os_string();
+        path.push(".sock");
+        return path.into();
+    }
+    let dirs = project_dirs();
+    dirs.runtime_dir()
+        .unwrap_or_else(|| dirs.cache_dir())
+        .join("daemon.sock")
+}
+
+/// Moves the database out of the cache dir, where cleaners may wipe it.
+pub fn migrate_legacy_db(path: &Path) -> Result<()> {
+    if db_override().is_some() || portable_dir().is_some() {
+        return Ok(());
+    }
+    let legacy = legacy_db_path();
+    if path.join("data.mdb").exists() || !legacy.join("data.mdb").exists() {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context("cannot migrate database")?;
+    }
+    if std::fs::rename(&legacy, path).is_err() {
+        // different filesystems
+        std::fs::create_dir_all(path).context("cannot migrate database")?;
+        std::fs::copy(legacy.join("data.mdb"), path.join("data.mdb"))
+            .context("cannot migrate database")?;
+        std::fs::remove_dir_all(&legacy).context("cannot migrate database")?;
+    }
+    crate::log::info!(
+        "moved database from {} to {}",
+        legacy.display(),
+        path.display()
+    );
+    Ok(())
+}
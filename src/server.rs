@@ -0,0 +1,320 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    api,
+    control::{Request, Response, Status},
+    database::{self, Database},
+    log, paths, show_error, tracker,
+};
+
+/// How long to wait for further scrobbles before syncing.
+const DEBOUNCE: Duration = Duration::from_secs(5);
+/// How often connectivity is probed while some anime is waiting for a retry.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time to wait for a retry due at `at`, both unix seconds. Retries already
+/// due wait for the shortest backoff, so that ones a sync cannot clear, like
+/// without a login, do not make the daemon spin.
+fn retry_delay(at: u64, now: u64) -> Duration {
+    Duration::from_secs(at.saturating_sub(now).max(database::RETRY_BASE))
+}
+
+#[derive(Debug, Default)]
+struct State {
+    deadline: Option<Instant>,
+    /// ignore retry backoff on the next sync
+    force: bool,
+    syncing: bool,
+    last_sync: Option<Instant>,
+    last_error: Option<String>,
+}
+
+struct Shared {
+    db: Database,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Shared {
+    /// Schedules a sync after `delay`, replacing any already scheduled one so
+    /// that bursts of scrobbles end up in a single sync.
+    fn request_sync(&self, delay: Duration, force: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.deadline = Some(Instant::now() + delay);
+        state.force |= force;
+        self.cond.notify_all();
+    }
+
+    /// Schedules a sync at `at` (unix seconds) unless an earlier one is
+    /// already scheduled.
+    fn schedule_retry(&self, at: u64) {
+        let deadline = Instant::now() + retry_delay(at, database::now());
+        let mut state = self.state.lock().unwrap();
+        state.deadline = Some(state.deadline.map_or(deadline, |d| d.min(deadline)));
+        self.cond.notify_all();
+    }
+
+    fn wait_sync(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match state.deadline {
+                None => state = self.cond.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        break;
+                    }
+                    state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+                }
+            }
+        }
+        state.deadline = None;
+        state.syncing = true;
+        core::mem::take(&mut state.force)
+    }
+
+    fn sync_loop(&self) {
+        loop {
+            let force = self.wait_sync();
+            let res = crate::sync_database(&self.db, force);
+            if let Err(ref err) = res {
+                show_error(err);
+            }
+            {
+                let mut state = self.state.lock().unwrap();
+                state.syncing = false;
+                state.last_sync = Some(Instant::now());
+                state.last_error = res.err().map(|err| err.to_string());
+            }
+            match self.db.next_retry() {
+                Ok(Some(at)) => self.schedule_retry(at),
+                Ok(None) => (),
+                Err(err) => show_error(err),
+            }
+        }
+    }
+
+    /// Retries everything right away when the network comes back after an
+    /// outage, instead of waiting for the backoff to expire.
+    fn watch_connectivity(&self) {
+        let mut reachable = true;
+        loop {
+            std::thread::sleep(PROBE_INTERVAL);
+            match self.db.next_retry() {
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    reachable = true;
+                    continue;
+                }
+                Err(err) => {
+                    show_error(err);
+                    continue;
+                }
+            }
+            let now_reachable = api::is_reachable();
+            if now_reachable && !reachable {
+                log::info!("network is reachable again, retrying pending scrobbles");
+                self.request_sync(Duration::ZERO, true);
+            }
+            reachable = now_reachable;
+        }
+    }
+
+    fn status(&self) -> Result<Status> {
+        let pending = self.db.pending_len()?;
+        let next_retry = self
+            .db
+            .next_retry()?
+            .map(|at| at.saturating_sub(database::now()));
+        let state = self.state.lock().unwrap();
+        Ok(Status {
+            pending,
+            manga_pending: self.db.manga_pending_len()?,
+            syncing: state.syncing,
+            last_sync: state.last_sync.map(|t| t.elapsed().as_secs()),
+            last_error: state.last_error.clone(),
+            next_retry,
+        })
+    }
+
+    fn handle(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::Scrobble {
+                anilist_id,
+                episode,
+                force,
+                group,
+                change,
+            } => {
+                let accounts = match group {
+                    Some(ref group) => match self.db.group(group)? {
+                        Some(accounts) => accounts,
+                        None => {
+                            return Ok(Response::Error {
+                                message: format!("unknown group {group}"),
+                            });
+                        }
+                    },
+                    None => Vec::new(),
+                };
+                // only checked against the cache, not to hold up the daemon
+                let episode =
+                    match tracker::check_episode(&self.db, anilist_id, episode, force, false) {
+                        Ok(episode) => episode,
+                        Err(err) => {
+                            return Ok(Response::Error {
+                                message: err.to_string(),
+                            });
+                        }
+                    };
+                if self
+                    .db
+                    .scrobble(anilist_id, episode, force, change.as_ref(), &accounts)?
+                {
+                    log::info!("started a rewatch of {anilist_id}");
+                }
+                log::info!("scrobbled {anilist_id} episode {episode} (force: {force})");
+                self.request_sync(DEBOUNCE, false);
+                Ok(Response::Ok)
+            }
+            Request::Rate { anilist_id, change } => {
+                self.db.queue_change(anilist_id, &change)?;
+                log::info!("queued list change for {anilist_id}");
+                self.request_sync(DEBOUNCE, false);
+                Ok(Response::Ok)
+            }
+            Request::Read {
+                manga_id,
+                chapter,
+                volume,
+            } => {
+                if self.db.read(manga_id, chapter, volume)? {
+                    log::info!("read {manga_id} chapter {chapter}");
+                    self.request_sync(DEBOUNCE, false);
+                }
+                Ok(Response::Ok)
+            }
+            Request::Sync => {
+                self.request_sync(Duration::ZERO, true);
+                Ok(Response::Ok)
+            }
+            Request::Status => self.status().map(Response::Status),
+        }
+    }
+
+    fn serve(&self, stream: UnixStream) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut line = String::new();
+        let mut reader = BufReader::new(stream);
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            log::debug!("request: {}", line.trim_end());
+            let response = match serde_json::from_str(&line)
+                .context("invalid request")
+                .and_then(|request| self.handle(request))
+            {
+                Ok(response) => response,
+                Err(err) => Response::Error {
+                    message: format!("{err:#}"),
+                },
+            };
+            let mut buf = serde_json::to_vec(&response)?;
+            buf.push(b'\n');
+            writer.write_all(&buf)?;
+        }
+    }
+}
+
+/// Removes the socket file when the daemon exits.
+struct Socket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Socket {
+    fn bind() -> Result<Self> {
+        let path = paths::socket_path();
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                bail!("daemon already running");
+            }
+            std::fs::remove_file(&path).context("cannot remove stale socket")?;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("cannot create socket")?;
+        }
+        let listener = UnixListener::bind(&path).context("cannot create socket")?;
+        Ok(Self { listener, path })
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub fn run(db: Database) -> Result<()> {
+    let socket = Socket::bind()?;
+    log::info!("daemon listening on {}", socket.path.display());
+    let shared = Arc::new(Shared {
+        db,
+        state: Mutex::new(State::default()),
+        cond: Condvar::new(),
+    });
+
+    // leftovers from previous runs
+    shared.request_sync(Duration::ZERO, false);
+    {
+        let shared = shared.clone();
+        std::thread::spawn(move || shared.sync_loop());
+    }
+    {
+        let shared = shared.clone();
+        std::thread::spawn(move || shared.watch_connectivity());
+    }
+
+    for stream in socket.listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    if let Err(err) = shared.serve(stream) {
+                        show_error(err);
+                    }
+                });
+            }
+            Err(err) => show_error(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::retry_delay;
+    use crate::database::RETRY_BASE;
+
+    #[test]
+    fn retry_delay_waits_at_least_the_base() {
+        assert_eq!(retry_delay(1_000, 1_000), Duration::from_secs(RETRY_BASE));
+        assert_eq!(retry_delay(10, 1_000), Duration::from_secs(RETRY_BASE));
+        assert_eq!(
+            retry_delay(1_000 + RETRY_BASE + 5, 1_000),
+            Duration::from_secs(RETRY_BASE + 5)
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{
+    api::{self, ListChange, MediaListStatus},
+    database::{Credentials, Database},
+};
+
+mod anilist;
+#[cfg(test)]
+pub(crate) mod fake;
+pub mod kitsu;
+pub mod mal;
+
+pub use anilist::{AniList, check_episode, fetch_media};
+pub use kitsu::Kitsu;
+pub use mal::Mal;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TrackerKind {
+    #[default]
+    Anilist,
+    Mal,
+    Kitsu,
+}
+
+/// Prefix of the trackers of AniList accounts other than the main one,
+/// followed by the account name.
+pub const ACCOUNT_PREFIX: &str = "anilist:";
+
+/// An anime found by [`Tracker::search`], with the tracker's own id.
+#[derive(Debug)]
+pub struct SearchResult {
+    pub id: u64,
+    pub title: Option<String>,
+    pub episodes: Option<u64>,
+}
+
+/// A site the pending queue is synced to. Anime are always identified by
+/// their AniList id, each tracker mapping them to its own ids.
+pub trait Tracker {
+    /// Name under which credentials and sync state are stored.
+    fn name(&self) -> &str;
+
+    /// Id of the user the credentials belong to.
+    fn me(&self) -> Result<u64, ureq::Error>;
+
+    fn get_entry(&self, id: u64) -> Result<api::Anime, ureq::Error>;
+
+    /// Saves `progress`, along with the other fields in `change`, returning
+    /// the progress stored by the tracker. The progress and the status are
+    /// left as they are when `None`, see [`save_status`].
+    fn save_entry(
+        &self,
+        id: u64,
+        progress: Option<u64>,
+        change: &ListChange,
+    ) -> Result<u64, ureq::Error>;
+
+    fn search(&self, query: &str) -> Result<Vec<SearchResult>, ureq::Error>;
+}
+
+/// Status to save along with `progress`: the one asked in `change` if any,
+/// else one derived from `progress` when it moves. When only the other list
+/// fields change, the remote status is kept, and anime not in the list yet
+/// are added as planned.
+pub fn save_status(
+    change: Option<&ListChange>,
+    progress: u64,
+    total: Option<u64>,
+    remote_progress: u64,
+    remote_status: Option<MediaListStatus>,
+) -> Option<MediaListStatus> {
+    if let Some(status) = change.and_then(|change| change.status) {
+        return Some(status);
+    }
+    if progress != remote_progress {
+        return Some(if total == Some(progress) {
+            MediaListStatus::Completed
+        } else {
+            MediaListStatus::Current
+        });
+    }
+    match remote_status {
+        Some(_) => None,
+        None => Some(MediaListStatus::Planning),
+    }
+}
+
+/// Wraps errors that are not from HTTP, like database ones.
+fn other(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ureq::Error {
+    ureq::Error::Other(err.into())
+}
+
+/// Builds the tracker stored as `name`, `None` if unknown to this version.
+pub fn open(db: &Database, name: &str, credentials: Credentials) -> Option<Box<dyn Tracker>> {
+    if name.starts_with(ACCOUNT_PREFIX) {
+        return Some(Box::new(AniList::account(
+            db.clone(),
+            name,
+            crate::database::User {
+                token: credentials.token,
+                id: credentials.user_id,
+            },
+        )));
+    }
+    match name {
+        "mal" => Some(Box::new(Mal::new(db.clone(), credentials))),
+        "kitsu" => Some(Box::new(Kitsu::new(db.clone(), credentials))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::save_status;
+    use crate::api::{
+        ListChange,
+        MediaListStatus::{Completed, Current, Paused, Planning},
+    };
+
+    #[test]
+    fn save_status_only_moves_with_progress() {
+        let paused = ListChange {
+            status: Some(Paused),
+            ..Default::default()
+        };
+        // `(change, progress, remote progress, remote status, expected)`
+        let cases = [
+            (Some(&paused), 5, 3, Some(Current), Some(Paused)),
+            (None, 5, 3, Some(Paused), Some(Current)),
+            (None, 12, 3, Some(Paused), Some(Completed)),
+            (None, 5, 3, None, Some(Current)),
+            (Some(&ListChange::default()), 3, 3, Some(Paused), None),
+            (Some(&ListChange::default()), 0, 0, None, Some(Planning)),
+        ];
+        for (change, progress, remote, remote_status, expected) in cases {
+            assert_eq!(
+                save_status(change, progress, Some(12), remote, remote_status),
+                expected,
+                "{change:?} {progress} {remote} {remote_status:?}"
+            );
+        }
+    }
+}
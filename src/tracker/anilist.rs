@@ -0,0 +1,139 @@
+use anyhow::bail;
+
+use super::{SearchResult, Tracker, other};
+use crate::{
+    api::{self, Api, ListChange, MediaInfo, SearchFilter},
+    database::{Database, User, now},
+    log,
+};
+
+pub struct AniList {
+    api: Api,
+    db: Database,
+    name: String,
+    user: User,
+}
+
+impl AniList {
+    /// The main account, whose login is kept in `main`.
+    pub fn new(db: Database, user: User) -> Self {
+        Self::account(db, "anilist", user)
+    }
+
+    /// An extra account, stored as tracker `name`.
+    pub fn account(db: Database, name: &str, user: User) -> Self {
+        Self {
+            api: Api::new(),
+            db,
+            name: name.to_string(),
+            user,
+        }
+    }
+}
+
+/// Details of `id`, fetched only if the cached ones expired.
+pub fn fetch_media(db: &Database, id: u64) -> Result<MediaInfo, ureq::Error> {
+    if let Some(media) = db.media(id).map_err(other)?
+        && media.is_fresh(now())
+    {
+        return Ok(media.info);
+    }
+    let info = Api::new().get_media(id)?;
+    db.set_media(id, &info).map_err(other)?;
+    Ok(info)
+}
+
+/// Checks `episode` of `id` against its episode count and airing schedule,
+/// fetched from AniList if not cached or expired and `fetch` is set.
+/// Episodes past the last one are clamped to it and episodes not aired yet
+/// are rejected, unless `force` is set.
+pub fn check_episode(
+    db: &Database,
+    id: u64,
+    episode: u64,
+    force: bool,
+    fetch: bool,
+) -> anyhow::Result<u64> {
+    let now = now();
+    let cached = db.media(id)?.map(|media| media.info);
+    let info = if fetch {
+        match fetch_media(db, id) {
+            Ok(info) => Some(info),
+            Err(err) => {
+                log::debug!("cannot fetch the episodes of {id}: {err}");
+                cached
+            }
+        }
+    } else {
+        cached
+    };
+    let Some(info) = info else {
+        return Ok(episode);
+    };
+
+    if let Some(episodes) = info.episodes
+        && episode > episodes
+    {
+        if !force {
+            log::warning!(
+                "{id} has {episodes} episodes, scrobbling {episodes} instead of {episode}"
+            );
+            return Ok(episodes);
+        }
+        log::warning!("{id} has {episodes} episodes, scrobbling {episode} anyway");
+    }
+    if let Some(next) = info.next_airing
+        && episode >= next.episode
+        && next.airing_at > now
+    {
+        let airing = format!(
+            "episode {} of {id} airs at {}",
+            next.episode,
+            log::timestamp(next.airing_at)
+        );
+        if !force {
+            bail!("{airing}, use --force to scrobble {episode} anyway");
+        }
+        log::warning!("{airing}, scrobbling {episode} anyway");
+    }
+    Ok(episode)
+}
+
+impl Tracker for AniList {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn me(&self) -> Result<u64, ureq::Error> {
+        self.api.me(&self.user.token)
+    }
+
+    fn get_entry(&self, id: u64) -> Result<api::Anime, ureq::Error> {
+        let media = fetch_media(&self.db, id)?;
+        self.api
+            .get_progress(&self.user.token, self.user.id, id, media)
+    }
+
+    fn save_entry(
+        &self,
+        id: u64,
+        progress: Option<u64>,
+        change: &ListChange,
+    ) -> Result<u64, ureq::Error> {
+        self.api
+            .set_progress(&self.user.token, id, progress, change)
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<SearchResult>, ureq::Error> {
+        Ok(self
+            .api
+            .search(query, &SearchFilter::default())?
+            .into_iter()
+            .map(|media| SearchResult {
+                id: media.id,
+                title: media.title.userPreferred,
+                episodes: media.episodes,
+            })
+            .collect())
+    }
+}
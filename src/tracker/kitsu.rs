@@ -0,0 +1,661 @@
+use std::cell::RefCell;
+
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use ureq::RequestBuilder;
+
+use super::{SearchResult, Tracker, other};
+use crate::{
+    api::{self, FuzzyDate, ListChange, MediaListStatus},
+    database::{Credentials, Database, now},
+};
+
+const API: &str = "https://kitsu.app/api/edge";
+pub const OAUTH: &str = "https://kitsu.app/api/oauth";
+const JSON_API: &str = "application/vnd.api+json";
+/// Tokens are refreshed when expiring within this many seconds.
+const REFRESH_MARGIN: u64 = 60 * 60;
+
+pub struct Kitsu {
+    agent: ureq::Agent,
+    db: Database,
+    credentials: RefCell<Credentials>,
+    /// base URL of the API
+    api: String,
+    /// base URL of the OAuth endpoints
+    oauth: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    refresh_token: String,
+    expires_in: u64,
+}
+
+impl Token {
+    fn into_credentials(self) -> Credentials {
+        Credentials {
+            user_id: 0,
+            token: self.access_token,
+            refresh_token: Some(self.refresh_token),
+            expires_at: Some(now() + self.expires_in),
+            client_id: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Document<T> {
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct Resource<A> {
+    id: String,
+    attributes: A,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EntryAttributes {
+    #[serde(default)]
+    progress: u64,
+    updated_at: Option<String>,
+    status: Option<String>,
+    #[serde(default)]
+    reconsuming: bool,
+    #[serde(default)]
+    reconsume_count: u64,
+}
+
+impl EntryAttributes {
+    fn status(&self) -> Option<MediaListStatus> {
+        if self.reconsuming {
+            return Some(MediaListStatus::Repeating);
+        }
+        match self.status.as_deref()? {
+            "current" => Some(MediaListStatus::Current),
+            "completed" => Some(MediaListStatus::Completed),
+            "on_hold" => Some(MediaListStatus::Paused),
+            "dropped" => Some(MediaListStatus::Dropped),
+            "planned" => Some(MediaListStatus::Planning),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AnimeAttributes {
+    canonical_title: Option<String>,
+    episode_count: Option<u64>,
+}
+
+fn parse_id(id: &str) -> Result<u64, ureq::Error> {
+    id.parse()
+        .map_err(|_| other(format!("invalid Kitsu id {id:?}")))
+}
+
+fn status_name(status: MediaListStatus) -> &'static str {
+    match status {
+        MediaListStatus::Current | MediaListStatus::Repeating => "current",
+        MediaListStatus::Completed => "completed",
+        MediaListStatus::Paused => "on_hold",
+        MediaListStatus::Dropped => "dropped",
+        MediaListStatus::Planning => "planned",
+    }
+}
+
+/// Kitsu only takes full dates.
+fn format_date(date: FuzzyDate) -> Option<String> {
+    Some(format!(
+        "{:04}-{:02}-{:02}T00:00:00.000Z",
+        date.year?, date.month?, date.day?
+    ))
+}
+
+/// Logs in with the password grant at the `oauth` endpoints, usually
+/// [`OAUTH`]. The user id is left to fill in with [`Tracker::me`].
+pub fn login(oauth: &str, username: &str, password: &str) -> Result<Credentials, ureq::Error> {
+    let token = ureq::Agent::new_with_defaults()
+        .post(format!("{oauth}/token"))
+        .send_form([
+            ("grant_type", "password"),
+            ("username", username),
+            ("password", password),
+        ])?
+        .into_body()
+        .read_json::<Token>()?;
+    Ok(token.into_credentials())
+}
+
+impl Kitsu {
+    pub fn new(db: Database, credentials: Credentials) -> Self {
+        Self::with_urls(db, credentials, API, OAUTH)
+    }
+
+    /// Talks to the API at `api` and refreshes tokens at `oauth`.
+    pub fn with_urls(db: Database, credentials: Credentials, api: &str, oauth: &str) -> Self {
+        Self {
+            agent: ureq::Agent::new_with_defaults(),
+            db,
+            credentials: RefCell::new(credentials),
+            api: api.to_string(),
+            oauth: oauth.to_string(),
+        }
+    }
+
+    /// Access token, refreshed and saved first if about to expire.
+    fn token(&self) -> Result<String, ureq::Error> {
+        let mut credentials = self.credentials.borrow_mut();
+        if let (Some(expires_at), Some(refresh_token)) =
+            (credentials.expires_at, &credentials.refresh_token)
+            && expires_at <= now() + REFRESH_MARGIN
+        {
+            let refresh_token = refresh_token.clone();
+            let token = self
+                .agent
+                .post(format!("{}/token", self.oauth))
+                .send_form([
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token.as_str()),
+                ])?
+                .into_body()
+                .read_json::<Token>()?;
+            let user_id = credentials.user_id;
+            *credentials = Credentials {
+                user_id,
+                ..token.into_credentials()
+            };
+            // a login of a different account is not overwritten
+            if self
+                .db
+                .tracker(self.name())
+                .map_err(other)?
+                .is_some_and(|stored| stored.user_id == user_id)
+            {
+                self.db
+                    .set_tracker(self.name(), &credentials)
+                    .map_err(other)?;
+            }
+        }
+        Ok(credentials.token.clone())
+    }
+
+    fn get(&self, path: &str) -> Result<RequestBuilder<ureq::typestate::WithoutBody>, ureq::Error> {
+        Ok(self
+            .agent
+            .get(format!("{}{path}", self.api))
+            .header("Accept", JSON_API)
+            .header("Authorization", format!("Bearer {}", self.token()?)))
+    }
+
+    /// Kitsu id of a MyAnimeList or AniList anime, from the Kitsu mappings.
+    fn find_mapping(&self, site: &str, id: u64) -> Result<Option<u64>, ureq::Error> {
+        #[derive(Deserialize)]
+        struct Item {
+            id: String,
+        }
+
+        #[derive(Deserialize)]
+        struct Mappings {
+            #[serde(default)]
+            included: Vec<Item>,
+        }
+
+        self.get("/mappings")?
+            .query("filter[externalSite]", site)
+            .query("filter[externalId]", id.to_string())
+            .query("include", "item")
+            .call()?
+            .into_body()
+            .read_json::<Mappings>()?
+            .included
+            .first()
+            .map(|item| parse_id(&item.id))
+            .transpose()
+    }
+
+    fn kitsu_id(&self, id: u64) -> Result<u64, ureq::Error> {
+        if let Some(kitsu_id) = self.db.mapping(self.name(), id).map_err(other)? {
+            return Ok(kitsu_id);
+        }
+        let kitsu_id = match self.find_mapping("anilist/anime", id)? {
+            Some(kitsu_id) => Some(kitsu_id),
+            // Kitsu knows more MyAnimeList ids than AniList ones
+            None => match super::fetch_media(&self.db, id)?.id_mal {
+                Some(mal_id) => self.find_mapping("myanimelist/anime", mal_id)?,
+                None => None,
+            },
+        };
+        let Some(kitsu_id) = kitsu_id else {
+            return Err(other(format!("{id} has no Kitsu id")));
+        };
+        self.db
+            .set_mapping(self.name(), id, kitsu_id)
+            .map_err(other)?;
+        Ok(kitsu_id)
+    }
+
+    fn library_entry(
+        &self,
+        kitsu_id: u64,
+    ) -> Result<Option<Resource<EntryAttributes>>, ureq::Error> {
+        let user_id = self.credentials.borrow().user_id;
+        Ok(self
+            .get("/library-entries")?
+            .query("filter[userId]", user_id.to_string())
+            .query("filter[animeId]", kitsu_id.to_string())
+            .call()?
+            .into_body()
+            .read_json::<Document<Vec<Resource<EntryAttributes>>>>()?
+            .data
+            .into_iter()
+            .next())
+    }
+}
+
+impl Tracker for Kitsu {
+    fn name(&self) -> &str {
+        "kitsu"
+    }
+
+    fn me(&self) -> Result<u64, ureq::Error> {
+        #[derive(Deserialize)]
+        struct User {
+            id: String,
+        }
+
+        let users = self
+            .get("/users")?
+            .query("filter[self]", "true")
+            .call()?
+            .into_body()
+            .read_json::<Document<Vec<User>>>()?;
+        match users.data.first() {
+            Some(user) => parse_id(&user.id),
+            None => Err(other("no Kitsu user for this token")),
+        }
+    }
+
+    fn get_entry(&self, id: u64) -> Result<api::Anime, ureq::Error> {
+        let kitsu_id = self.kitsu_id(id)?;
+        let anime = self
+            .get(&format!("/anime/{kitsu_id}"))?
+            .call()?
+            .into_body()
+            .read_json::<Document<Resource<AnimeAttributes>>>()?
+            .data
+            .attributes;
+        let entry = self.library_entry(kitsu_id)?.map(|entry| entry.attributes);
+        Ok(api::Anime {
+            title: anime.canonical_title,
+            episodes: anime.episode_count,
+            progress: entry.as_ref().map_or(0, |entry| entry.progress),
+            updated_at: entry
+                .as_ref()
+                .and_then(|entry| entry.updated_at.as_deref())
+                .and_then(crate::log::parse_timestamp),
+            status: entry.as_ref().and_then(EntryAttributes::status),
+            repeat: entry.as_ref().map_or(0, |entry| entry.reconsume_count),
+        })
+    }
+
+    fn save_entry(
+        &self,
+        id: u64,
+        progress: Option<u64>,
+        change: &ListChange,
+    ) -> Result<u64, ureq::Error> {
+        let kitsu_id = self.kitsu_id(id)?;
+
+        let mut attributes = Map::new();
+        if let Some(progress) = progress {
+            attributes.insert("progress".to_string(), json!(progress));
+        }
+        if let Some(status) = change.status {
+            attributes.insert("status".to_string(), json!(status_name(status)));
+            attributes.insert(
+                "reconsuming".to_string(),
+                json!(status == MediaListStatus::Repeating),
+            );
+        }
+        if let Some(score) = change.score {
+            // 0 removes the rating, 2 is the lowest one: 0-10 maps to 2-20
+            let rating = (score > 0.0).then(|| ((score * 2.0).round() as u64).clamp(2, 20));
+            attributes.insert("ratingTwenty".to_string(), json!(rating));
+        }
+        if let Some(repeat) = change.repeat {
+            attributes.insert("reconsumeCount".to_string(), json!(repeat));
+        }
+        if let Some(date) = change.started_at.and_then(format_date) {
+            attributes.insert("startedAt".to_string(), json!(date));
+        }
+        if let Some(date) = change.completed_at.and_then(format_date) {
+            attributes.insert("finishedAt".to_string(), json!(date));
+        }
+        if let Some(notes) = &change.notes {
+            attributes.insert("notes".to_string(), json!(notes));
+        }
+        if let Some(private) = change.private {
+            attributes.insert("private".to_string(), json!(private));
+        }
+        let attributes = Value::Object(attributes);
+
+        let token = self.token()?;
+        let response = match self.library_entry(kitsu_id)? {
+            Some(entry) => self
+                .agent
+                .patch(format!("{}/library-entries/{}", self.api, entry.id))
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Accept", JSON_API)
+                .header("Content-Type", JSON_API)
+                .send(serde_json::to_vec(&json!({
+                    "data": {
+                        "type": "libraryEntries",
+                        "id": entry.id,
+                        "attributes": attributes,
+                    }
+                }))?)?,
+            None => self
+                .agent
+                .post(format!("{}/library-entries", self.api))
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Accept", JSON_API)
+                .header("Content-Type", JSON_API)
+                .send(serde_json::to_vec(&json!({
+                    "data": {
+                        "type": "libraryEntries",
+                        "attributes": attributes,
+                        "relationships": {
+                            "anime": { "data": { "type": "anime", "id": kitsu_id.to_string() } },
+                            "user": {
+                                "data": {
+                                    "type": "users",
+                                    "id": self.credentials.borrow().user_id.to_string(),
+                                }
+                            },
+                        },
+                    }
+                }))?)?,
+        };
+        Ok(response
+            .into_body()
+            .read_json::<Document<Resource<EntryAttributes>>>()?
+            .data
+            .attributes
+            .progress)
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<SearchResult>, ureq::Error> {
+        Ok(self
+            .get("/anime")?
+            .query("filter[text]", query)
+            .query("page[limit]", "20")
+            .call()?
+            .into_body()
+            .read_json::<Document<Vec<Resource<AnimeAttributes>>>>()?
+            .data
+            .into_iter()
+            .filter_map(|anime| {
+                Some(SearchResult {
+                    id: anime.id.parse().ok()?,
+                    title: anime.attributes.canonical_title,
+                    episodes: anime.attributes.episode_count,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::{Kitsu, login};
+    use crate::{
+        api::{ListChange, MediaListStatus},
+        database::{Credentials, now, testing::TempDatabase},
+        tracker::{Tracker, fake::FakeServer},
+    };
+
+    const TOKEN: &str = r#"{"access_token":"new","token_type":"bearer","expires_in":2592000,"refresh_token":"refresh2","scope":"public","created_at":1705312800}"#;
+    const ANIME: &str = r#"{"data":{"id":"1","type":"anime","attributes":{"canonicalTitle":"Cowboy Bebop","episodeCount":26}}}"#;
+
+    fn credentials(expires_at: u64) -> Credentials {
+        Credentials {
+            user_id: 7,
+            token: "old".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: Some(expires_at),
+            client_id: None,
+        }
+    }
+
+    fn kitsu(db: &TempDatabase, server: &FakeServer) -> Kitsu {
+        Kitsu::with_urls(
+            (*db).clone(),
+            credentials(now() + 86400),
+            &format!("{}/edge", server.url),
+            &format!("{}/oauth", server.url),
+        )
+    }
+
+    fn entry(progress: u64) -> String {
+        json!({
+            "data": {
+                "id": "55",
+                "type": "libraryEntries",
+                "attributes": {
+                    "progress": progress,
+                    "status": "current",
+                    "reconsuming": false,
+                    "reconsumeCount": 1,
+                    "updatedAt": "2024-01-15T10:00:00.000Z"
+                }
+            }
+        })
+        .to_string()
+    }
+
+    fn entries(progress: u64) -> String {
+        let mut entry: Value = serde_json::from_str(&entry(progress)).unwrap();
+        entry["data"] = json!([entry["data"].take()]);
+        entry.to_string()
+    }
+
+    #[test]
+    fn logs_in_with_password() {
+        let server = FakeServer::start(vec![(200, TOKEN.to_string())]);
+        let credentials = login(&format!("{}/oauth", server.url), "someone", "a&b").unwrap();
+        assert_eq!(credentials.token, "new");
+        assert_eq!(credentials.refresh_token.as_deref(), Some("refresh2"));
+
+        let requests = server.finish();
+        assert_eq!(requests[0].path, "/oauth/token");
+        assert_eq!(
+            requests[0].form_value("grant_type").as_deref(),
+            Some("password")
+        );
+        assert_eq!(
+            requests[0].form_value("username").as_deref(),
+            Some("someone")
+        );
+        assert_eq!(requests[0].form_value("password").as_deref(), Some("a&b"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn refreshes_expiring_token() {
+        let db = TempDatabase::new();
+        let server = FakeServer::start(vec![
+            (200, TOKEN.to_string()),
+            (200, r#"{"data":[{"id":"7","type":"users"}]}"#.to_string()),
+        ]);
+        db.set_tracker("kitsu", &credentials(now() + 60)).unwrap();
+        let kitsu = Kitsu::with_urls(
+            (*db).clone(),
+            credentials(now() + 60),
+            &format!("{}/edge", server.url),
+            &format!("{}/oauth", server.url),
+        );
+        assert_eq!(kitsu.me().unwrap(), 7);
+
+        let requests = server.finish();
+        assert_eq!(
+            requests[0].form_value("grant_type").as_deref(),
+            Some("refresh_token")
+        );
+        assert_eq!(requests[1].path, "/edge/users?filter%5Bself%5D=true");
+        assert_eq!(requests[1].header("authorization"), Some("Bearer new"));
+        assert_eq!(db.tracker("kitsu").unwrap().unwrap().token, "new");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn gets_entry_through_mappings() {
+        let db = TempDatabase::new();
+        let server = FakeServer::start(vec![
+            (
+                200,
+                r#"{"data":[{"id":"9","type":"mappings"}],"included":[{"id":"1","type":"anime"}]}"#
+                    .to_string(),
+            ),
+            (200, ANIME.to_string()),
+            (200, entries(5)),
+        ]);
+        let entry = kitsu(&db, &server).get_entry(1).unwrap();
+        assert_eq!(entry.title.as_deref(), Some("Cowboy Bebop"));
+        assert_eq!(entry.episodes, Some(26));
+        assert_eq!(entry.progress, 5);
+        assert_eq!(entry.status, Some(MediaListStatus::Current));
+        assert_eq!(entry.repeat, 1);
+        assert_eq!(entry.updated_at, Some(1705312800));
+        assert_eq!(db.mapping("kitsu", 1).unwrap(), Some(1));
+
+        let requests = server.finish();
+        assert!(requests[0].path.starts_with("/edge/mappings?"));
+        assert!(requests[0].path.contains("anilist%2Fanime"));
+        assert_eq!(requests[1].path, "/edge/anime/1");
+        assert_eq!(
+            requests[2].path,
+            "/edge/library-entries?filter%5BuserId%5D=7&filter%5BanimeId%5D=1"
+        );
+        for request in &requests {
+            assert_eq!(request.header("accept"), Some("application/vnd.api+json"));
+            assert_eq!(request.header("authorization"), Some("Bearer old"));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn updates_entry() {
+        let db = TempDatabase::new();
+        db.set_mapping("kitsu", 1, 1).unwrap();
+        let server = FakeServer::start(vec![(200, entries(5)), (200, entry(6))]);
+        let change = ListChange {
+            score: Some(0.0),
+            notes: Some("notes".to_string()),
+            ..Default::default()
+        };
+        let progress = kitsu(&db, &server).save_entry(1, None, &change).unwrap();
+        assert_eq!(progress, 6);
+
+        let requests = server.finish();
+        assert_eq!(requests[1].method, "PATCH");
+        assert_eq!(requests[1].path, "/edge/library-entries/55");
+        assert_eq!(
+            requests[1].header("content-type"),
+            Some("application/vnd.api+json")
+        );
+        let body: Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "data": {
+                    "type": "libraryEntries",
+                    "id": "55",
+                    // the progress and the status are left as they are
+                    "attributes": {
+                        // a score of 0 removes the rating
+                        "ratingTwenty": null,
+                        "notes": "notes",
+                    }
+                }
+            })
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn creates_entry() {
+        let db = TempDatabase::new();
+        db.set_mapping("kitsu", 1, 1).unwrap();
+        let server = FakeServer::start(vec![(200, r#"{"data":[]}"#.to_string()), (201, entry(26))]);
+        let change = ListChange {
+            status: Some(MediaListStatus::Completed),
+            score: Some(0.4),
+            ..Default::default()
+        };
+        let progress = kitsu(&db, &server)
+            .save_entry(1, Some(26), &change)
+            .unwrap();
+        assert_eq!(progress, 26);
+
+        let requests = server.finish();
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].path, "/edge/library-entries");
+        let body: Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "data": {
+                    "type": "libraryEntries",
+                    "attributes": {
+                        "progress": 26,
+                        "status": "completed",
+                        "reconsuming": false,
+                        // the lowest rating
+                        "ratingTwenty": 2,
+                    },
+                    "relationships": {
+                        "anime": { "data": { "type": "anime", "id": "1" } },
+                        "user": { "data": { "type": "users", "id": "7" } },
+                    },
+                }
+            })
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn searches() {
+        let db = TempDatabase::new();
+        let server = FakeServer::start(vec![(
+            200,
+            r#"{"data": [
+                {"id": "1", "attributes": {"canonicalTitle": "Cowboy Bebop", "episodeCount": 26}},
+                {"id": "2", "attributes": {"canonicalTitle": "Cowboy Bebop: Tengoku no Tobira", "episodeCount": null}}
+            ]}"#
+            .to_string(),
+        )]);
+        let results = kitsu(&db, &server).search("bebop").unwrap();
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| (result.id, result.title.as_deref(), result.episodes))
+                .collect::<Vec<_>>(),
+            [
+                (1, Some("Cowboy Bebop"), Some(26)),
+                (2, Some("Cowboy Bebop: Tengoku no Tobira"), None),
+            ]
+        );
+
+        let requests = server.finish();
+        assert_eq!(
+            requests[0].path,
+            "/edge/anime?filter%5Btext%5D=bebop&page%5Blimit%5D=20"
+        );
+    }
+}
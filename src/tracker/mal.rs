@@ -0,0 +1,567 @@
+use std::cell::RefCell;
+
+use serde::Deserialize;
+
+use super::{SearchResult, Tracker, other};
+use crate::{
+    api::{self, FuzzyDate, ListChange, MediaListStatus},
+    database::{Credentials, Database, now},
+};
+
+const API: &str = "https://api.myanimelist.net/v2";
+pub const OAUTH: &str = "https://myanimelist.net/v1/oauth2";
+/// Tokens are refreshed when expiring within this many seconds.
+const REFRESH_MARGIN: u64 = 60 * 60;
+
+pub struct Mal {
+    agent: ureq::Agent,
+    db: Database,
+    credentials: RefCell<Credentials>,
+    /// base URL of the API
+    api: String,
+    /// base URL of the OAuth endpoints
+    oauth: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    refresh_token: String,
+    expires_in: u64,
+}
+
+impl Token {
+    fn into_credentials(self, client_id: &str) -> Credentials {
+        Credentials {
+            user_id: 0,
+            token: self.access_token,
+            refresh_token: Some(self.refresh_token),
+            expires_at: Some(now() + self.expires_in),
+            client_id: Some(client_id.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ListStatus {
+    #[serde(default)]
+    num_episodes_watched: u64,
+    updated_at: Option<String>,
+    status: Option<String>,
+    #[serde(default)]
+    is_rewatching: bool,
+    #[serde(default)]
+    num_times_rewatched: u64,
+}
+
+impl ListStatus {
+    fn status(&self) -> Option<MediaListStatus> {
+        if self.is_rewatching {
+            return Some(MediaListStatus::Repeating);
+        }
+        match self.status.as_deref()? {
+            "watching" => Some(MediaListStatus::Current),
+            "completed" => Some(MediaListStatus::Completed),
+            "on_hold" => Some(MediaListStatus::Paused),
+            "dropped" => Some(MediaListStatus::Dropped),
+            "plan_to_watch" => Some(MediaListStatus::Planning),
+            _ => None,
+        }
+    }
+}
+
+fn status_name(status: MediaListStatus) -> &'static str {
+    match status {
+        MediaListStatus::Current => "watching",
+        MediaListStatus::Completed | MediaListStatus::Repeating => "completed",
+        MediaListStatus::Paused => "on_hold",
+        MediaListStatus::Dropped => "dropped",
+        MediaListStatus::Planning => "plan_to_watch",
+    }
+}
+
+/// MAL only takes full dates.
+fn format_date(date: FuzzyDate) -> Option<String> {
+    Some(format!(
+        "{:04}-{:02}-{:02}",
+        date.year?, date.month?, date.day?
+    ))
+}
+
+/// PKCE code verifier. MAL only supports the `plain` method, so it is also
+/// the code challenge.
+pub fn new_verifier() -> Result<String, getrandom::Error> {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut bytes = [0u8; 64];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes
+        .iter()
+        .map(|b| CHARSET[(b & 63) as usize] as char)
+        .collect())
+}
+
+pub fn authorize_url(client_id: &str, verifier: &str) -> String {
+    format!(
+        "{OAUTH}/authorize?response_type=code&client_id={client_id}&code_challenge={verifier}&code_challenge_method=plain"
+    )
+}
+
+/// Trades the code from the authorization redirect for tokens at the `oauth`
+/// endpoints, usually [`OAUTH`]. The user id is left to fill in with
+/// [`Tracker::me`].
+pub fn exchange_code(
+    oauth: &str,
+    client_id: &str,
+    code: &str,
+    verifier: &str,
+) -> Result<Credentials, ureq::Error> {
+    let token = ureq::Agent::new_with_defaults()
+        .post(format!("{oauth}/token"))
+        .send_form([
+            ("client_id", client_id),
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("code_verifier", verifier),
+        ])?
+        .into_body()
+        .read_json::<Token>()?;
+    Ok(token.into_credentials(client_id))
+}
+
+impl Mal {
+    pub fn new(db: Database, credentials: Credentials) -> Self {
+        Self::with_urls(db, credentials, API, OAUTH)
+    }
+
+    /// Talks to the API at `api` and refreshes tokens at `oauth`.
+    pub fn with_urls(db: Database, credentials: Credentials, api: &str, oauth: &str) -> Self {
+        Self {
+            agent: ureq::Agent::new_with_defaults(),
+            db,
+            credentials: RefCell::new(credentials),
+            api: api.to_string(),
+            oauth: oauth.to_string(),
+        }
+    }
+
+    /// Access token, refreshed and saved first if about to expire.
+    fn token(&self) -> Result<String, ureq::Error> {
+        let mut credentials = self.credentials.borrow_mut();
+        if let (Some(expires_at), Some(refresh_token), Some(client_id)) = (
+            credentials.expires_at,
+            &credentials.refresh_token,
+            &credentials.client_id,
+        ) && expires_at <= now() + REFRESH_MARGIN
+        {
+            let (refresh_token, client_id) = (refresh_token.clone(), client_id.clone());
+            let token = self
+                .agent
+                .post(format!("{}/token", self.oauth))
+                .send_form([
+                    ("client_id", client_id.as_str()),
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token.as_str()),
+                ])?
+                .into_body()
+                .read_json::<Token>()?;
+            let user_id = credentials.user_id;
+            *credentials = Credentials {
+                user_id,
+                ..token.into_credentials(&client_id)
+            };
+            // a login of a different account is not overwritten
+            if self
+                .db
+                .tracker(self.name())
+                .map_err(other)?
+                .is_some_and(|stored| stored.user_id == user_id)
+            {
+                self.db
+                    .set_tracker(self.name(), &credentials)
+                    .map_err(other)?;
+            }
+        }
+        Ok(credentials.token.clone())
+    }
+
+    fn mal_id(&self, id: u64) -> Result<u64, ureq::Error> {
+        if let Some(mal_id) = self.db.mapping(self.name(), id).map_err(other)? {
+            return Ok(mal_id);
+        }
+        let Some(mal_id) = super::fetch_media(&self.db, id)?.id_mal else {
+            return Err(other(format!("{id} has no MyAnimeList id")));
+        };
+        self.db
+            .set_mapping(self.name(), id, mal_id)
+            .map_err(other)?;
+        Ok(mal_id)
+    }
+}
+
+impl Tracker for Mal {
+    fn name(&self) -> &str {
+        "mal"
+    }
+
+    fn me(&self) -> Result<u64, ureq::Error> {
+        #[derive(Deserialize)]
+        struct User {
+            id: u64,
+        }
+
+        Ok(self
+            .agent
+            .get(format!("{}/users/@me", self.api))
+            .header("Authorization", format!("Bearer {}", self.token()?))
+            .call()?
+            .into_body()
+            .read_json::<User>()?
+            .id)
+    }
+
+    fn get_entry(&self, id: u64) -> Result<api::Anime, ureq::Error> {
+        #[derive(Deserialize)]
+        struct Anime {
+            title: Option<String>,
+            #[serde(default)]
+            num_episodes: u64,
+            my_list_status: Option<ListStatus>,
+        }
+
+        let anime = self
+            .agent
+            .get(format!("{}/anime/{}", self.api, self.mal_id(id)?))
+            .query(
+                "fields",
+                "num_episodes,my_list_status{status,num_episodes_watched,updated_at,is_rewatching,num_times_rewatched}",
+            )
+            .header("Authorization", format!("Bearer {}", self.token()?))
+            .call()?
+            .into_body()
+            .read_json::<Anime>()?;
+        let status = anime.my_list_status;
+        Ok(api::Anime {
+            title: anime.title,
+            // 0 while unknown
+            episodes: (anime.num_episodes != 0).then_some(anime.num_episodes),
+            progress: status.as_ref().map_or(0, |s| s.num_episodes_watched),
+            updated_at: status
+                .as_ref()
+                .and_then(|s| s.updated_at.as_deref())
+                .and_then(crate::log::parse_timestamp),
+            status: status.as_ref().and_then(ListStatus::status),
+            repeat: status.as_ref().map_or(0, |s| s.num_times_rewatched),
+        })
+    }
+
+    fn save_entry(
+        &self,
+        id: u64,
+        progress: Option<u64>,
+        change: &ListChange,
+    ) -> Result<u64, ureq::Error> {
+        let mut form = Vec::new();
+        if let Some(progress) = progress {
+            form.push(("num_watched_episodes", progress.to_string()));
+        }
+        if let Some(status) = change.status {
+            form.push(("status", status_name(status).to_string()));
+            form.push((
+                "is_rewatching",
+                (status == MediaListStatus::Repeating).to_string(),
+            ));
+        }
+        if let Some(score) = change.score {
+            form.push(("score", (score.round() as u64).to_string()));
+        }
+        if let Some(repeat) = change.repeat {
+            form.push(("num_times_rewatched", repeat.to_string()));
+        }
+        if let Some(date) = change.started_at.and_then(format_date) {
+            form.push(("start_date", date));
+        }
+        if let Some(date) = change.completed_at.and_then(format_date) {
+            form.push(("finish_date", date));
+        }
+        if let Some(notes) = &change.notes {
+            form.push(("comments", notes.clone()));
+        }
+
+        Ok(self
+            .agent
+            .patch(format!(
+                "{}/anime/{}/my_list_status",
+                self.api,
+                self.mal_id(id)?
+            ))
+            .header("Authorization", format!("Bearer {}", self.token()?))
+            .send_form(form.iter().map(|(k, v)| (*k, v.as_str())))?
+            .into_body()
+            .read_json::<ListStatus>()?
+            .num_episodes_watched)
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<SearchResult>, ureq::Error> {
+        #[derive(Deserialize)]
+        struct Node {
+            id: u64,
+            title: Option<String>,
+            #[serde(default)]
+            num_episodes: u64,
+        }
+
+        #[derive(Deserialize)]
+        struct Item {
+            node: Node,
+        }
+
+        #[derive(Deserialize)]
+        struct Page {
+            data: Vec<Item>,
+        }
+
+        Ok(self
+            .agent
+            .get(format!("{}/anime", self.api))
+            .query("q", query)
+            .query("limit", "20")
+            .query("fields", "num_episodes")
+            .header("Authorization", format!("Bearer {}", self.token()?))
+            .call()?
+            .into_body()
+            .read_json::<Page>()?
+            .data
+            .into_iter()
+            .map(|item| SearchResult {
+                id: item.node.id,
+                title: item.node.title,
+                episodes: (item.node.num_episodes != 0).then_some(item.node.num_episodes),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mal, exchange_code};
+    use crate::{
+        api::{FuzzyDate, ListChange, MediaListStatus},
+        database::{Credentials, now, testing::TempDatabase},
+        tracker::{Tracker, fake::FakeServer},
+    };
+
+    const TOKEN: &str = r#"{"token_type":"Bearer","expires_in":2678400,"access_token":"new","refresh_token":"refresh2"}"#;
+
+    fn credentials(expires_at: u64) -> Credentials {
+        Credentials {
+            user_id: 7,
+            token: "old".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: Some(expires_at),
+            client_id: Some("client".to_string()),
+        }
+    }
+
+    fn mal(db: &TempDatabase, server: &FakeServer, credentials: Credentials) -> Mal {
+        db.set_mapping("mal", 1, 101).unwrap();
+        Mal::with_urls(
+            (*db).clone(),
+            credentials,
+            &format!("{}/v2", server.url),
+            &format!("{}/oauth2", server.url),
+        )
+    }
+
+    #[test]
+    fn exchanges_code_with_verifier() {
+        let server = FakeServer::start(vec![(200, TOKEN.to_string())]);
+        let credentials = exchange_code(
+            &format!("{}/oauth2", server.url),
+            "client",
+            "code",
+            "verifier",
+        )
+        .unwrap();
+        assert_eq!(credentials.token, "new");
+        assert_eq!(credentials.refresh_token.as_deref(), Some("refresh2"));
+        assert_eq!(credentials.client_id.as_deref(), Some("client"));
+        assert!(credentials.expires_at.unwrap() >= now() + 2678400);
+
+        let requests = server.finish();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/oauth2/token");
+        for (key, value) in [
+            ("client_id", "client"),
+            ("grant_type", "authorization_code"),
+            ("code", "code"),
+            ("code_verifier", "verifier"),
+        ] {
+            assert_eq!(requests[0].form_value(key).as_deref(), Some(value), "{key}");
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn refreshes_expiring_token() {
+        let db = TempDatabase::new();
+        let server = FakeServer::start(vec![
+            (200, TOKEN.to_string()),
+            (200, r#"{"id":7,"name":"someone"}"#.to_string()),
+        ]);
+        db.set_tracker("mal", &credentials(now() + 60)).unwrap();
+        let mal = mal(&db, &server, credentials(now() + 60));
+        assert_eq!(mal.me().unwrap(), 7);
+
+        let requests = server.finish();
+        assert_eq!(requests[0].path, "/oauth2/token");
+        assert_eq!(
+            requests[0].form_value("grant_type").as_deref(),
+            Some("refresh_token")
+        );
+        assert_eq!(
+            requests[0].form_value("refresh_token").as_deref(),
+            Some("refresh")
+        );
+        assert_eq!(requests[1].path, "/v2/users/@me");
+        assert_eq!(requests[1].header("authorization"), Some("Bearer new"));
+
+        let stored = db.tracker("mal").unwrap().unwrap();
+        assert_eq!(stored.user_id, 7);
+        assert_eq!(stored.token, "new");
+        assert_eq!(stored.refresh_token.as_deref(), Some("refresh2"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn gets_entry() {
+        let db = TempDatabase::new();
+        let server = FakeServer::start(vec![(
+            200,
+            r#"{
+                "id": 101,
+                "title": "Cowboy Bebop",
+                "num_episodes": 26,
+                "my_list_status": {
+                    "status": "completed",
+                    "num_episodes_watched": 26,
+                    "is_rewatching": true,
+                    "num_times_rewatched": 2,
+                    "updated_at": "2024-01-15T10:00:00+00:00"
+                }
+            }"#
+            .to_string(),
+        )]);
+        let entry = mal(&db, &server, credentials(now() + 86400))
+            .get_entry(1)
+            .unwrap();
+        assert_eq!(entry.title.as_deref(), Some("Cowboy Bebop"));
+        assert_eq!(entry.episodes, Some(26));
+        assert_eq!(entry.progress, 26);
+        assert_eq!(entry.status, Some(MediaListStatus::Repeating));
+        assert_eq!(entry.repeat, 2);
+        assert_eq!(entry.updated_at, Some(1705312800));
+
+        let requests = server.finish();
+        assert_eq!(requests[0].method, "GET");
+        assert!(requests[0].path.starts_with("/v2/anime/101?fields="));
+        assert_eq!(requests[0].header("authorization"), Some("Bearer old"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn gets_missing_entry() {
+        let db = TempDatabase::new();
+        let server = FakeServer::start(vec![(
+            200,
+            r#"{"id": 101, "title": "Cowboy Bebop", "num_episodes": 0}"#.to_string(),
+        )]);
+        let entry = mal(&db, &server, credentials(now() + 86400))
+            .get_entry(1)
+            .unwrap();
+        assert_eq!(entry.episodes, None);
+        assert_eq!(entry.progress, 0);
+        assert_eq!(entry.status, None);
+        server.finish();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn saves_entry() {
+        let db = TempDatabase::new();
+        let server = FakeServer::start(vec![(
+            200,
+            r#"{"status":"completed","num_episodes_watched":26}"#.to_string(),
+        )]);
+        let change = ListChange {
+            score: Some(8.6),
+            started_at: Some(FuzzyDate {
+                year: Some(2024),
+                month: Some(1),
+                day: Some(2),
+            }),
+            completed_at: Some(FuzzyDate {
+                year: Some(2024),
+                month: None,
+                day: None,
+            }),
+            notes: Some("great & short".to_string()),
+            status: Some(MediaListStatus::Completed),
+            ..Default::default()
+        };
+        let progress = mal(&db, &server, credentials(now() + 86400))
+            .save_entry(1, Some(26), &change)
+            .unwrap();
+        assert_eq!(progress, 26);
+
+        let requests = server.finish();
+        assert_eq!(requests[0].method, "PATCH");
+        assert_eq!(requests[0].path, "/v2/anime/101/my_list_status");
+        let form = requests[0].form();
+        let expected = [
+            ("num_watched_episodes", "26"),
+            ("status", "completed"),
+            ("is_rewatching", "false"),
+            ("score", "9"),
+            ("start_date", "2024-01-02"),
+            ("comments", "great & short"),
+        ];
+        assert_eq!(
+            form,
+            expected.map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "LMDB is not supported by Miri")]
+    fn searches() {
+        let db = TempDatabase::new();
+        let server = FakeServer::start(vec![(
+            200,
+            r#"{"data": [
+                {"node": {"id": 1, "title": "Cowboy Bebop", "num_episodes": 26}},
+                {"node": {"id": 5, "title": "Cowboy Bebop: Tengoku no Tobira", "num_episodes": 0}}
+            ]}"#
+            .to_string(),
+        )]);
+        let results = mal(&db, &server, credentials(now() + 86400))
+            .search("bebop")
+            .unwrap();
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| (result.id, result.title.as_deref(), result.episodes))
+                .collect::<Vec<_>>(),
+            [
+                (1, Some("Cowboy Bebop"), Some(26)),
+                (5, Some("Cowboy Bebop: Tengoku no Tobira"), None),
+            ]
+        );
+
+        let requests = server.finish();
+        assert_eq!(
+            requests[0].path,
+            "/v2/anime?q=bebop&limit=20&fields=num_episodes"
+        );
+        assert_eq!(requests[0].header("authorization"), Some("Bearer old"));
+    }
+}